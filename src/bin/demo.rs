@@ -1,34 +1,19 @@
 pub use fcm_push_listener::Error;
-use fcm_push_listener::{new_heartbeat_ack, MessageStream, Registration, Session as GcmSession, WebPushKeys};
-use tokio::io::AsyncWriteExt;
+use fcm_push_listener::{PushListener, Registration, Session as GcmSession, WebPushKeys};
 
-async fn run(registration: Registration) -> Result<(), fcm_push_listener::Error> {
-    use tokio_stream::StreamExt;
+async fn run(registration: Registration) {
+    let mut listener = PushListener::new(registration, vec![]);
 
-    let http = reqwest::Client::new();
-    let session = registration.gcm.checkin(&http).await?;
-    let connection = session.new_connection(vec![]).await?;
-    let mut stream = MessageStream::wrap(connection, &registration.keys);
-
-    while let Some(message) = stream.next().await {
-        match message? {
-            fcm_push_listener::Message::Data(data) => {
+    loop {
+        match listener.next().await {
+            Ok(data) => {
                 println!("Message {:?} Data: {:?}", data.persistent_id, data.body);
             }
-            fcm_push_listener::Message::HeartbeatPing => {
-                println!("Heartbeat");
-                let result = stream.write_all(&new_heartbeat_ack()).await;
-                if let Err(e) = result {
-                    println!("Error sending heartbeat ack: {:?}", e);
-                }
-            }
-            fcm_push_listener::Message::Other(tag, bytes) => {
-                println!("Got non-data message: {tag:?}, {bytes:?}");
+            Err(e) => {
+                println!("Error receiving push messages: {:?}", e);
             }
         }
     }
-
-    Ok(())
 }
 
 #[tokio::main]
@@ -46,6 +31,9 @@ async fn main() {
             private_key: vec![],
             public_key: vec![],
         },
+        gcm_token: "def".to_owned(),
+        gcm_app_id: "wp:receiver.push.com#ghi".to_owned(),
+        installation: None,
     };
 
     tokio::spawn(run(registration));
@@ -1,5 +1,5 @@
 use std::ffi::{CStr, CString, c_char, c_void};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, LazyLock, Mutex};
 use std::collections::HashMap;
 use base64::Engine;
 use tokio::sync::mpsc;
@@ -7,8 +7,8 @@ use tokio::sync::mpsc;
 // Используем типы из нашей библиотеки
 use crate::{
     register::{register, Registration},
-    gcm::CheckedSession,
-    push::{MessageStream, Message, new_heartbeat_ack},
+    PushListener,
+    Endpoints,
     WebPushKeys,
     Session as GcmSession,
 };
@@ -58,6 +58,11 @@ pub type RegistrationCallback = extern "C" fn(i32, *const CFcmRegistration, *mut
 pub type MessageCallback = extern "C" fn(*const CFcmMessage, *mut c_void);
 pub type ErrorCallback = extern "C" fn(i32, *const c_char, *mut c_void);
 
+// Указатель пользователя передается в callback'и из фонового потока
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
 // Структура для хранения состояния слушателя
 struct ListenerState {
     registration: Registration,
@@ -66,7 +71,8 @@ struct ListenerState {
 }
 
 // Глобальное хранилище регистраций и слушателей
-static REGISTRATIONS: Mutex<HashMap<u64, Arc<Mutex<ListenerState>>>> = Mutex::new(HashMap::new());
+static REGISTRATIONS: LazyLock<Mutex<HashMap<u64, Arc<Mutex<ListenerState>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
 static mut NEXT_ID: u64 = 1;
 
 // Thread-local Runtime
@@ -157,7 +163,10 @@ pub extern "C" fn fcm_register_async(
         }
     };
 
+    let user_data = UserData(user_data);
+
     std::thread::spawn(move || {
+        let user_data = user_data;
         get_runtime().ok();
         
         let result = RUNTIME.with(|runtime| {
@@ -165,7 +174,7 @@ pub extern "C" fn fcm_register_async(
             if let Some(ref rt) = *rt {
                 rt.block_on(async {
                     let http = reqwest::Client::new();
-                    register(&http, &app_id, &project_id, &api_key, vapid_key.as_deref()).await
+                    register(&http, &Endpoints::default(), &app_id, &project_id, &api_key, vapid_key.as_deref()).await
                 })
             } else {
                 Err(crate::Error::DependencyFailure("runtime", "not initialized"))
@@ -213,11 +222,11 @@ pub extern "C" fn fcm_register_async(
                     public_key: public_key_cstring.as_ptr(),
                 };
 
-                callback(FCM_SUCCESS, &c_registration, user_data);
+                callback(FCM_SUCCESS, &c_registration, user_data.0);
             }
             Err(e) => {
                 let error_msg = CString::new(format!("{}", e)).unwrap_or_default();
-                callback(FCM_ERROR_NETWORK, std::ptr::null(), user_data);
+                callback(FCM_ERROR_NETWORK, std::ptr::null(), user_data.0);
                 drop(error_msg);
             }
         }
@@ -294,6 +303,9 @@ pub extern "C" fn fcm_create_registration_from_data(
             private_key,
             public_key,
         },
+        gcm_token: String::new(),
+        gcm_app_id: String::new(),
+        installation: None,
     };
 
     // Генерируем ID и сохраняем
@@ -368,7 +380,10 @@ pub extern "C" fn fcm_start_listening(
         state_guard.stop_sender = Some(stop_sender);
     }
 
+    let user_data = UserData(user_data);
+
     std::thread::spawn(move || {
+        let user_data = user_data;
         get_runtime().ok();
         
         RUNTIME.with(|runtime| {
@@ -380,96 +395,43 @@ pub extern "C" fn fcm_start_listening(
                         state_guard.registration.clone()
                     };
 
-                    let http = reqwest::Client::new();
-                    
+                    let mut listener = PushListener::new(registration, received_persistent_ids);
+
+                    // Слушаем сообщения, переподключение выполняет PushListener
                     loop {
-                        // Checkin
-                        let session = match registration.gcm.checkin(&http).await {
-                            Ok(s) => s,
-                            Err(e) => {
-                                let error_msg = CString::new(format!("Checkin failed: {}", e)).unwrap_or_default();
-                                error_callback(FCM_ERROR_NETWORK, error_msg.as_ptr(), user_data);
+                        tokio::select! {
+                            _ = stop_receiver.recv() => {
+                                // Получили сигнал остановки
                                 break;
                             }
-                        };
-
-                        // Подключаемся
-                        let connection = match session.new_connection(received_persistent_ids.clone()).await {
-                            Ok(c) => c,
-                            Err(e) => {
-                                let error_msg = CString::new(format!("Connection failed: {}", e)).unwrap_or_default();
-                                error_callback(FCM_ERROR_NETWORK, error_msg.as_ptr(), user_data);
-                                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-                                continue;
-                            }
-                        };
-
-                        let mut stream = MessageStream::wrap(connection, &registration.keys);
-                        
-                        // Слушаем сообщения
-                        loop {
-                            use tokio_stream::StreamExt;
-                            
-                            tokio::select! {
-                                _ = stop_receiver.recv() => {
-                                    // Получили сигнал остановки
-                                    break;
-                                }
-                                message = stream.next() => {
-                                    match message {
-                                        Some(Ok(Message::Data(data))) => {
-                                            // Отправляем сообщение через callback
-                                            let persistent_id_cstring = data.persistent_id
-                                                .as_ref()
-                                                .and_then(|id| CString::new(id.clone()).ok())
-                                                .unwrap_or_default();
-                                            
-                                            let c_message = CFcmMessage {
-                                                persistent_id: if data.persistent_id.is_some() { 
-                                                    persistent_id_cstring.as_ptr() 
-                                                } else { 
-                                                    std::ptr::null() 
-                                                },
-                                                body: data.body.as_ptr() as *const c_void,
-                                                body_len: data.body.len(),
-                                            };
-
-                                            message_callback(&c_message, user_data);
-                                            
-                                            // Добавляем ID в список полученных
-                                            if let Some(id) = data.persistent_id {
-                                                received_persistent_ids.push(id);
-                                            }
-                                        }
-                                        Some(Ok(Message::HeartbeatPing)) => {
-                                            // Отправляем heartbeat ack
-                                            use tokio::io::AsyncWriteExt;
-                                            let _ = stream.write_all(&new_heartbeat_ack()).await;
-                                        }
-                                        Some(Ok(Message::Other(_, _))) => {
-                                            // Игнорируем другие сообщения
-                                        }
-                                        Some(Err(e)) => {
-                                            let error_msg = CString::new(format!("Stream error: {}", e)).unwrap_or_default();
-                                            error_callback(FCM_ERROR_NETWORK, error_msg.as_ptr(), user_data);
-                                            break;
-                                        }
-                                        None => {
-                                            // Соединение закрыто
-                                            break;
-                                        }
+                            message = listener.next() => {
+                                match message {
+                                    Ok(data) => {
+                                        // Отправляем сообщение через callback
+                                        let persistent_id_cstring = data.persistent_id
+                                            .as_ref()
+                                            .and_then(|id| CString::new(id.clone()).ok())
+                                            .unwrap_or_default();
+                                        
+                                        let c_message = CFcmMessage {
+                                            persistent_id: if data.persistent_id.is_some() { 
+                                                persistent_id_cstring.as_ptr() 
+                                            } else { 
+                                                std::ptr::null() 
+                                            },
+                                            body: data.body.as_ptr() as *const c_void,
+                                            body_len: data.body.len(),
+                                        };
+
+                                        message_callback(&c_message, user_data.0);
+                                    }
+                                    Err(e) => {
+                                        let error_msg = CString::new(format!("Listener error: {}", e)).unwrap_or_default();
+                                        error_callback(FCM_ERROR_NETWORK, error_msg.as_ptr(), user_data.0);
                                     }
                                 }
                             }
                         }
-                        
-                        // Проверяем, не остановлены ли мы
-                        if stop_receiver.try_recv().is_ok() {
-                            break;
-                        }
-                        
-                        // Ждем перед переподключением
-                        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
                     }
                 });
             }
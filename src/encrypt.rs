@@ -0,0 +1,177 @@
+use crate::push::{encode_frame, ContentEncoding, MessageTag};
+use crate::{mcs, Error};
+use base64::engine::general_purpose::{URL_SAFE, URL_SAFE_NO_PAD};
+use base64::Engine;
+use bytes::BytesMut;
+
+/// A push payload encrypted for a receiver's web push keys.
+///
+/// This is the sending half of [`DataMessage`](crate::DataMessage) decryption: a stanza built from
+/// it decodes back to the plaintext with the matching registration.
+#[derive(Clone, Debug)]
+pub struct EncryptedPayload {
+    pub encoding: ContentEncoding,
+
+    /// Ciphertext, for `aes128gcm` including the header carrying salt and sender key
+    pub body: Vec<u8>,
+
+    /// `Crypto-Key` value, whose `dh=` parameter holds the sender's public key, only used by
+    /// `aesgcm`
+    pub crypto_key: Option<String>,
+
+    /// `Encryption` value, whose `salt=` parameter holds the salt, only used by `aesgcm`
+    pub encryption: Option<String>,
+}
+
+impl EncryptedPayload {
+    /// Encrypts `plaintext` for the receiver with the given public key and auth secret, as found
+    /// in [`WebPushKeys`](crate::WebPushKeys).
+    ///
+    /// A new sender key and salt are generated for every call.
+    pub fn encrypt(
+        public_key: &[u8],
+        auth_secret: &[u8],
+        plaintext: &[u8],
+        encoding: ContentEncoding,
+    ) -> Result<Self, Error> {
+        const OPERATION: &str = "message encryption";
+
+        match encoding {
+            ContentEncoding::Aes128Gcm => {
+                let body = ece::encrypt(public_key, auth_secret, plaintext)
+                    .map_err(|e| Error::Crypto(OPERATION, e))?;
+                Ok(Self {
+                    encoding,
+                    body,
+                    crypto_key: None,
+                    encryption: None,
+                })
+            }
+            ContentEncoding::AesGcm => {
+                let block = ece::legacy::encrypt_aesgcm(public_key, auth_secret, plaintext)
+                    .map_err(|e| Error::Crypto(OPERATION, e))?;
+
+                // ece only hands out the parameters as unpadded header values, receivers expect
+                // them padded
+                let mut dh = None;
+                let mut salt = None;
+                for (name, value) in block.headers(None) {
+                    match name {
+                        "Crypto-Key" => dh = Some(reencode("crypto-key", &value, "dh=")?),
+                        "Encryption" => salt = Some(reencode("encryption", &value, "salt=")?),
+                        _ => {}
+                    }
+                }
+
+                let body = URL_SAFE_NO_PAD
+                    .decode(block.body())
+                    .map_err(|e| Error::Base64Decode("encrypted payload", e))?;
+
+                Ok(Self {
+                    encoding,
+                    body,
+                    crypto_key: Some(format!(
+                        "dh={}",
+                        dh.ok_or(Error::MissingCryptoMetadata("crypto-key"))?
+                    )),
+                    encryption: Some(format!(
+                        "salt={}",
+                        salt.ok_or(Error::MissingCryptoMetadata("encryption"))?
+                    )),
+                })
+            }
+        }
+    }
+
+    /// HTTP headers to send along with `body` in a web push request
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![("Content-Encoding", self.encoding.as_str().to_string())];
+        if let Some(ref crypto_key) = self.crypto_key {
+            headers.push(("Crypto-Key", crypto_key.clone()));
+        }
+        if let Some(ref encryption) = self.encryption {
+            headers.push(("Encryption", encryption.clone()));
+        }
+
+        headers
+    }
+
+    /// the encryption parameters as they appear in a data message's `app_data`
+    pub fn app_data(&self) -> Vec<mcs::AppData> {
+        self.headers()
+            .into_iter()
+            .map(|(name, value)| mcs::AppData {
+                key: name.to_ascii_lowercase(),
+                value,
+            })
+            .collect()
+    }
+
+    /// A data message stanza carrying this payload, as the push service would deliver it.
+    ///
+    /// Sender, category and the other metadata can be filled in on the returned stanza.
+    pub fn data_message_stanza(&self, persistent_id: &str) -> mcs::DataMessageStanza {
+        mcs::DataMessageStanza {
+            id: Some(persistent_id.to_string()),
+            category: "org.chromium.linux".into(),
+            persistent_id: Some(persistent_id.to_string()),
+            app_data: self.app_data(),
+            raw_data: Some(self.body.clone()),
+            ..Default::default()
+        }
+    }
+
+    /// the stanza from [`data_message_stanza`](Self::data_message_stanza), encoded as an MCS frame
+    /// ready to be written after login
+    pub fn data_message_frame(&self, persistent_id: &str) -> BytesMut {
+        encode_frame(
+            MessageTag::DataMessageStanza,
+            &self.data_message_stanza(persistent_id),
+        )
+    }
+}
+
+/// strips `prefix` and any trailing parameters off an ece header value and pads the base64
+fn reencode(kind: &'static str, value: &str, prefix: &str) -> Result<String, Error> {
+    let value = value.strip_prefix(prefix).unwrap_or(value);
+    let value = value.split(';').next().unwrap_or_default();
+    let bytes = URL_SAFE_NO_PAD
+        .decode(value)
+        .map_err(|e| Error::Base64Decode(kind, e))?;
+
+    Ok(URL_SAFE.encode(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push::{Message, MessageStream};
+    use crate::WebPushKeys;
+    use tokio_stream::StreamExt;
+
+    async fn round_trip(encoding: ContentEncoding, plaintext: &[u8]) {
+        let keys = WebPushKeys::new().unwrap();
+        let payload =
+            EncryptedPayload::encrypt(&keys.public_key, &keys.auth_secret, plaintext, encoding)
+                .unwrap();
+        let frame = payload.data_message_frame("0:1");
+
+        let mut stream = MessageStream::new(std::io::Cursor::new(frame.to_vec()), &keys);
+        let Some(Ok(Message::Data(message))) = stream.next().await else {
+            panic!("the frame should decode to a data message");
+        };
+
+        assert_eq!(message.body, plaintext);
+        assert_eq!(message.persistent_id.as_deref(), Some("0:1"));
+    }
+
+    #[tokio::test]
+    async fn aes128gcm_round_trip() {
+        round_trip(ContentEncoding::Aes128Gcm, b"hello").await;
+    }
+
+    #[tokio::test]
+    async fn aesgcm_round_trip() {
+        round_trip(ContentEncoding::AesGcm, b"hello").await;
+    }
+}
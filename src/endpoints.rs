@@ -0,0 +1,40 @@
+/// Addresses of the Google services used for registration and listening.
+///
+/// The defaults point at Google; override them to go through regional mirrors, egress gateways,
+/// or a local stand-in server.
+#[derive(Clone, Debug)]
+pub struct Endpoints {
+    /// Android device check-in
+    pub checkin: String,
+
+    /// GCM registration (`register3`)
+    pub register: String,
+
+    /// Base URL of the Firebase installations API
+    pub installations: String,
+
+    /// Base URL of the FCM registrations API
+    pub fcm_registrations: String,
+
+    /// Base of the push endpoint handed to FCM, the GCM token is appended to it
+    pub fcm_send: String,
+
+    /// Host of the MCS server, also the name its TLS certificate is checked against
+    pub mcs_host: String,
+
+    pub mcs_port: u16,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            checkin: "https://android.clients.google.com/checkin".into(),
+            register: "https://android.clients.google.com/c2dm/register3".into(),
+            installations: "https://firebaseinstallations.googleapis.com/v1".into(),
+            fcm_registrations: "https://fcmregistrations.googleapis.com/v1".into(),
+            fcm_send: "https://fcm.googleapis.com/fcm/send".into(),
+            mcs_host: "mtalk.google.com".into(),
+            mcs_port: 5228,
+        }
+    }
+}
@@ -1,4 +1,7 @@
+use crate::{HttpResponse, TransportError};
 use std::error;
+use std::future::Future;
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug)]
 pub enum Error {
@@ -8,14 +11,68 @@ pub enum Error {
     DependencyRejection(&'static str, String),
     /// Received an encrypted message with no decryption params
     MissingCryptoMetadata(&'static str),
+    /// Received a message encrypted with a scheme we don't know
+    UnsupportedContentEncoding(String),
     /// Protobuf deserialization failure, probably a contract change
     ProtobufDecode(&'static str, prost::DecodeError),
     EmptyPayload,
-    Request(&'static str, reqwest::Error),
-    Response(&'static str, reqwest::Error),
+    /// The HTTP transport got no response
+    Request(&'static str, TransportError),
     Base64Decode(&'static str, base64::DecodeError),
     Crypto(&'static str, ece::Error),
     Socket(std::io::Error),
+    /// The push service stopped answering heartbeats, the connection should be re-established
+    ConnectionStale,
+    /// The push service refused the MCS login
+    LoginRejected {
+        code: i32,
+        message: Option<String>,
+        kind: Option<String>,
+    },
+    /// VAPID key handling or token signing failed
+    Vapid(&'static str, openssl::error::ErrorStack),
+    /// The push service refused a web push message; 404 and 410 mean the subscription is gone
+    PushRejected {
+        status: u16,
+        reason: String,
+        /// how long the push service asked us to wait before trying again
+        retry_after: Option<Duration>,
+    },
+    /// Reading or writing persisted state failed
+    Storage(&'static str, std::io::Error),
+    Json(&'static str, serde_json::Error),
+    /// Sealing or opening registration secrets failed
+    Seal(&'static str, openssl::error::ErrorStack),
+    /// The sealed registration doesn't open with the given key, or it was altered
+    WrongSealingKey,
+    /// The saved registration is sealed but no key was given to open it
+    SealingKeyRequired,
+    /// The sealed registration asks for scrypt parameters outside the accepted bounds
+    UnsupportedKeyDerivation,
+    /// A Google API answered with an error status
+    GoogleApi {
+        api: &'static str,
+        status: u16,
+        /// Google's status name, e.g. `PERMISSION_DENIED` or `RESOURCE_EXHAUSTED`
+        google_status: Option<String>,
+        message: String,
+        /// how long the service asked us to wait before trying again
+        retry_after: Option<Duration>,
+    },
+    /// The proxy refused or failed to open a tunnel
+    Proxy(String),
+    /// The TLS settings for the MCS connection can't be put together
+    TlsConfig(String),
+    /// A step of registration or connection setup didn't finish in time
+    Timeout(&'static str),
+    /// A data message arrived but couldn't be decrypted or decoded. [`PushListener`] acknowledges
+    /// it, it won't be delivered again.
+    ///
+    /// [`PushListener`]: crate::PushListener
+    UndecodableMessage {
+        persistent_id: Option<String>,
+        error: Box<Error>,
+    },
 }
 
 impl std::fmt::Display for Error {
@@ -26,13 +83,77 @@ impl std::fmt::Display for Error {
                 write!(f, "{api} API rejected request: {reason}")
             }
             Self::MissingCryptoMetadata(kind) => write!(f, "Missing {kind} metadata on message"),
+            Self::UnsupportedContentEncoding(encoding) => {
+                write!(f, "Unsupported content encoding {encoding}")
+            }
             Self::ProtobufDecode(kind, e) => write!(f, "Error decoding {kind}: {e}"),
             Self::EmptyPayload => write!(f, "Received a data message with no payload"),
             Self::Base64Decode(kind, e) => write!(f, "Error decoding {kind}: {e}"),
             Self::Request(kind, e) => write!(f, "{kind} API request error: {e}"),
-            Self::Response(kind, e) => write!(f, "{kind} API response error: {e}"),
             Self::Crypto(kind, e) => write!(f, "Crypto {kind} error: {e}"),
             Self::Socket(e) => write!(f, "TCP error: {e}"),
+            Self::ConnectionStale => write!(f, "Push service connection is stale"),
+            Self::LoginRejected {
+                code,
+                message,
+                kind,
+            } => {
+                write!(f, "MCS login rejected with code {code}")?;
+                if let Some(kind) = kind {
+                    write!(f, " ({kind})")?;
+                }
+                if let Some(message) = message {
+                    write!(f, ": {message}")?;
+                }
+                Ok(())
+            }
+            Self::Vapid(kind, e) => write!(f, "VAPID {kind} error: {e}"),
+            Self::PushRejected { status, reason, .. } => {
+                write!(
+                    f,
+                    "Push service rejected the message with status {status}: {reason}"
+                )
+            }
+            Self::Storage(kind, e) => write!(f, "Storage error during {kind}: {e}"),
+            Self::Json(kind, e) => write!(f, "Error handling {kind} JSON: {e}"),
+            Self::Seal(kind, e) => write!(f, "Sealing {kind} error: {e}"),
+            Self::WrongSealingKey => write!(
+                f,
+                "Sealed registration can't be opened, the key is wrong or the data was altered"
+            ),
+            Self::SealingKeyRequired => {
+                write!(f, "Registration is sealed but no key was given to open it")
+            }
+            Self::UnsupportedKeyDerivation => write!(
+                f,
+                "Sealed registration asks for an unsupported or unreasonable scrypt cost"
+            ),
+            Self::GoogleApi {
+                api,
+                status,
+                google_status,
+                message,
+                ..
+            } => {
+                write!(f, "{api} API responded with status {status}")?;
+                if let Some(google_status) = google_status {
+                    write!(f, " ({google_status})")?;
+                }
+                write!(f, ": {message}")
+            }
+            Self::Proxy(reason) => write!(f, "Proxy error: {reason}"),
+            Self::TlsConfig(reason) => write!(f, "TLS configuration error: {reason}"),
+            Self::Timeout(stage) => write!(f, "{stage} timed out"),
+            Self::UndecodableMessage {
+                persistent_id,
+                error,
+            } => {
+                write!(f, "Data message")?;
+                if let Some(persistent_id) = persistent_id {
+                    write!(f, " {persistent_id}")?;
+                }
+                write!(f, " can't be read: {error}")
+            }
         }
     }
 }
@@ -43,13 +164,127 @@ impl std::error::Error for Error {
             Self::DependencyFailure(_, _) => None,
             Self::DependencyRejection(_, _) => None,
             Self::MissingCryptoMetadata(_) => None,
+            Self::UnsupportedContentEncoding(_) => None,
             Self::ProtobufDecode(_, ref e) => Some(e),
             Self::EmptyPayload => None,
             Self::Base64Decode(_, ref e) => Some(e),
             Self::Request(_, ref e) => Some(e),
-            Self::Response(_, ref e) => Some(e),
             Self::Crypto(_, ref e) => Some(e),
             Self::Socket(ref e) => Some(e),
+            Self::ConnectionStale => None,
+            Self::LoginRejected { .. } => None,
+            Self::Vapid(_, ref e) => Some(e),
+            Self::PushRejected { .. } => None,
+            Self::Storage(_, ref e) => Some(e),
+            Self::Json(_, ref e) => Some(e),
+            Self::Seal(_, ref e) => Some(e),
+            Self::WrongSealingKey => None,
+            Self::SealingKeyRequired => None,
+            Self::UnsupportedKeyDerivation => None,
+            Self::GoogleApi { .. } => None,
+            Self::Proxy(_) => None,
+            Self::TlsConfig(_) => None,
+            Self::Timeout(_) => None,
+            Self::UndecodableMessage { ref error, .. } => Some(error.as_ref()),
+        }
+    }
+}
+
+impl Error {
+    /// Whether the failure is likely to pass, so the call can be made again as is: network
+    /// failures, timeouts, a stale connection, and 429 or 5xx answers.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Request(_, e) => e.is_retryable(),
+            Self::Socket(_) | Self::ConnectionStale | Self::Timeout(_) => true,
+            Self::GoogleApi { status, .. } | Self::PushRejected { status, .. } => {
+                *status == 429 || (500..600).contains(status)
+            }
+            // GCM answers this while the device registration is still propagating
+            Self::DependencyRejection(_, reason) => reason == "PHONE_REGISTRATION_ERROR",
+            _ => false,
         }
     }
 }
+
+/// Runs `future`, failing with [`Error::Timeout`] for `stage` when it takes longer than `limit`.
+pub(crate) async fn time_limit<T>(
+    stage: &'static str,
+    limit: Option<Duration>,
+    future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    let Some(limit) = limit else {
+        return future.await;
+    };
+
+    tokio::time::timeout(limit, future)
+        .await
+        .map_err(|_| Error::Timeout(stage))?
+}
+
+/// Passes successful responses through and turns the others into [`Error::GoogleApi`], with the
+/// details of Google's `{"error": {"code", "message", "status"}}` body when there is one.
+pub(crate) fn check_status(
+    api: &'static str,
+    response: HttpResponse,
+) -> Result<HttpResponse, Error> {
+    if response.is_success() {
+        return Ok(response);
+    }
+
+    let retry_after = response.header("Retry-After").and_then(parse_retry_after);
+    let body = response.text();
+
+    let error = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .map(|mut json| json["error"].take())
+        .filter(serde_json::Value::is_object);
+    let (google_status, message) = match error {
+        Some(error) => (
+            error["status"].as_str().map(String::from),
+            error["message"].as_str().map(String::from),
+        ),
+        None => (None, Some(body.trim().to_string())),
+    };
+
+    Err(Error::GoogleApi {
+        api,
+        status: response.status,
+        google_status,
+        message: message
+            .filter(|message| !message.is_empty())
+            .unwrap_or_else(|| status_reason(response.status).into()),
+        retry_after,
+    })
+}
+
+/// the reason phrase of common HTTP statuses
+pub(crate) fn status_reason(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        410 => "Gone",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "unknown",
+    }
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date
+pub(crate) fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = httpdate::parse_http_date(value.trim()).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
@@ -1,14 +1,18 @@
-use crate::Error;
+use crate::error::check_status;
+use crate::{Endpoints, Error, HttpRequest, HttpTransport};
 use serde::{Deserialize, Serialize};
 
-fn to_base64<S: serde::ser::Serializer>(v: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+pub(crate) fn to_base64<S: serde::ser::Serializer>(
+    v: &[u8],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
     use base64::Engine;
 
     let str = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(v);
     serializer.serialize_str(&str)
 }
 
-fn from_base64<'de, D: serde::de::Deserializer<'de>>(
+pub(crate) fn from_base64<'de, D: serde::de::Deserializer<'de>>(
     deserializer: D,
 ) -> std::result::Result<Vec<u8>, D::Error> {
     use base64::Engine;
@@ -27,17 +31,15 @@ pub struct Registration {
 
 impl Registration {
     pub async fn request(
-        http: &reqwest::Client,
+        http: &dyn HttpTransport,
+        endpoints: &Endpoints,
         project_id: &str,
         api_key: &str,
         application_pub_key: Option<&str>,
         firebase_installation_auth_token: &str,
         gcm_token: &str,
     ) -> Result<Self, Error> {
-        const FCM_API: &str = "https://fcm.googleapis.com/fcm";
-        const FCM_REGISTRATION_API: &str = "https://fcmregistrations.googleapis.com/v1";
-
-        let endpoint = format!("{FCM_API}/send/{gcm_token}");
+        let endpoint = format!("{}/{gcm_token}", endpoints.fcm_send);
         let push_keys = WebPushKeys::new().map_err(|e| Error::Crypto("key creation", e))?;
         let request = RegisterRequest {
             web: WebRegistrationRequest {
@@ -52,20 +54,18 @@ impl Registration {
         const API_KEY_HEADER: &str = "x-goog-api-key";
         const AUTH_HEADER: &str = "x-goog-firebase-installations-auth";
 
-        let url = format!("{FCM_REGISTRATION_API}/projects/{project_id}/registrations");
-        let response = http
-            .post(url)
-            .json(&request)
+        let url = format!(
+            "{}/projects/{project_id}/registrations",
+            endpoints.fcm_registrations
+        );
+        let response = HttpRequest::post(url)
+            .json(API_NAME, &request)?
             .header(API_KEY_HEADER, api_key)
             .header(AUTH_HEADER, firebase_installation_auth_token)
-            .send()
-            .await
-            .map_err(|e| Error::Request(API_NAME, e))?;
+            .send(http, API_NAME)
+            .await?;
 
-        let response: RegisterResponse = response
-            .json()
-            .await
-            .map_err(|e| Error::Response(API_NAME, e))?;
+        let response: RegisterResponse = check_status(API_NAME, response)?.json(API_NAME)?;
 
         Ok(Self {
             fcm_token: response.token,
@@ -74,6 +74,34 @@ impl Registration {
     }
 }
 
+/// Deletes the FCM registration for `fcm_token`, FCM stops accepting messages for it.
+pub(crate) async fn delete_registration(
+    http: &dyn HttpTransport,
+    endpoints: &Endpoints,
+    project_id: &str,
+    api_key: &str,
+    firebase_installation_auth_token: &str,
+    fcm_token: &str,
+) -> Result<(), Error> {
+    const API_NAME: &str = "FCM registration deletion";
+
+    let url = format!(
+        "{}/projects/{project_id}/registrations/{fcm_token}",
+        endpoints.fcm_registrations
+    );
+    let response = HttpRequest::delete(url)
+        .header("x-goog-api-key", api_key)
+        .header(
+            "x-goog-firebase-installations-auth",
+            firebase_installation_auth_token,
+        )
+        .send(http, API_NAME)
+        .await?;
+
+    check_status(API_NAME, response)?;
+    Ok(())
+}
+
 #[derive(Serialize)]
 struct RegisterRequest<'a> {
     web: WebRegistrationRequest<'a>,
@@ -115,7 +143,7 @@ pub struct WebPushKeys {
 }
 
 impl WebPushKeys {
-    fn new() -> Result<Self, ece::Error> {
+    pub(crate) fn new() -> Result<Self, ece::Error> {
         let (key_pair, auth_secret) = ece::generate_keypair_and_auth_secret()?;
         let components = key_pair.raw_components()?;
         Ok(WebPushKeys {
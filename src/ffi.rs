@@ -2,7 +2,7 @@ use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
 use std::ptr;
 
-use crate::{register, Registration};
+use crate::{register, Endpoints, Registration};
 
 // Add this to your src/lib.rs:
 // #[cfg(feature = "ffi")]
@@ -95,6 +95,7 @@ pub extern "C" fn fcm_register(
         let http = reqwest::Client::new();
         register(
             &http,
+            &Endpoints::default(),
             &app_id,
             &project_id,
             &api_key,
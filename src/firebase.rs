@@ -1,7 +1,12 @@
-use crate::Error;
+use crate::error::check_status;
+use crate::{Endpoints, Error, HttpRequest, HttpTransport};
 use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
 
-const INSTALL_API: &str = "https://firebaseinstallations.googleapis.com/v1";
+const SDK_VERSION: &str = "w:0.6.4";
+
+/// Auth tokens this close to expiring are refreshed before use
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60 * 60);
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -15,23 +20,90 @@ struct InstallationRequest<'a> {
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct InstallationResponse {
-    auth_token: InstallationAuthToken,
-    // fid: String,
-    // name: String,
-    // refresh_token: String,
+    auth_token: AuthTokenResponse,
+    fid: Option<String>,
+    name: String,
+    refresh_token: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateAuthTokenRequest<'a> {
+    installation: GenerateAuthTokenInstallation<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateAuthTokenInstallation<'a> {
+    app_id: &'a str,
+    sdk_version: &'a str,
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
+struct AuthTokenResponse {
+    token: String,
+    expires_in: Option<String>,
+}
+
+impl AuthTokenResponse {
+    fn into_token(self) -> InstallationAuthToken {
+        // durations come as seconds with an "s" suffix, e.g. "604800s"
+        let expires_at = self
+            .expires_in
+            .as_deref()
+            .and_then(|v| v.trim_end_matches('s').parse::<f64>().ok())
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+            .map(|lifetime| SystemTime::now() + lifetime);
+
+        InstallationAuthToken {
+            value: self.token,
+            expires_at,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct InstallationAuthToken {
-    // expires_in: String,
-    #[serde(rename = "token")]
     pub value: String,
+
+    /// `None` when the service didn't say
+    #[serde(default)]
+    pub expires_at: Option<SystemTime>,
 }
 
 impl InstallationAuthToken {
-    pub async fn request(
-        http: &reqwest::Client,
+    /// whether the token expires within the next hour
+    pub fn is_expiring(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= SystemTime::now() + EXPIRY_MARGIN)
+    }
+}
+
+/// A Firebase installation: the identity FCM registrations are made under.
+///
+/// Keeping it lets later FCM calls authenticate as the same installation, refreshing its auth
+/// token with the refresh token instead of creating a new installation.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Installation {
+    pub app_id: String,
+    pub project_id: String,
+    pub api_key: String,
+
+    /// Firebase installation ID
+    pub fid: String,
+
+    /// Resource name, `projects/{project_number}/installations/{fid}`
+    pub name: String,
+    pub refresh_token: String,
+    pub auth_token: InstallationAuthToken,
+}
+
+impl Installation {
+    /// registers a new installation with a freshly generated FID
+    pub async fn create(
+        http: &dyn HttpTransport,
+        endpoints: &Endpoints,
         application_id: &str,
         project_id: &str,
         api_key: &str,
@@ -45,7 +117,7 @@ impl InstallationAuthToken {
             app_id: application_id,
             auth_version: "FIS_v2",
             fid: &fid,
-            sdk_version: "w:0.6.4",
+            sdk_version: SDK_VERSION,
         };
 
         let heartbeat_json = "{\"heartbeats\": [], \"version\": 2}";
@@ -53,19 +125,93 @@ impl InstallationAuthToken {
 
         const API: &str = "Firebase installation";
 
-        let response = http
-            .post(format!("{INSTALL_API}/projects/{project_id}/installations"))
-            .json(&request)
-            .header("x-firebase-client", heartbeat_header_value)
-            .header("x-goog-api-key", api_key)
-            .send()
-            .await
-            .map_err(|e| Error::Request(API, e))?;
+        let response = HttpRequest::post(format!(
+            "{}/projects/{project_id}/installations",
+            endpoints.installations
+        ))
+        .json(API, &request)?
+        .header("x-firebase-client", heartbeat_header_value)
+        .header("x-goog-api-key", api_key)
+        .send(http, API)
+        .await?;
+
+        let response: InstallationResponse = check_status(API, response)?.json(API)?;
+
+        Ok(Self {
+            app_id: application_id.into(),
+            project_id: project_id.into(),
+            api_key: api_key.into(),
+            // the service may assign a different FID than the one we asked for
+            fid: response.fid.unwrap_or(fid),
+            name: response.name,
+            refresh_token: response.refresh_token,
+            auth_token: response.auth_token.into_token(),
+        })
+    }
+
+    /// gets a new auth token for this installation with its refresh token (`generateAuthToken`)
+    pub async fn generate_auth_token(
+        &mut self,
+        http: &dyn HttpTransport,
+        endpoints: &Endpoints,
+    ) -> Result<&InstallationAuthToken, Error> {
+        const API: &str = "Firebase installation auth token";
+
+        let request = GenerateAuthTokenRequest {
+            installation: GenerateAuthTokenInstallation {
+                app_id: &self.app_id,
+                sdk_version: SDK_VERSION,
+            },
+        };
+
+        let response = HttpRequest::post(format!(
+            "{}/projects/{}/installations/{}/authTokens:generate",
+            endpoints.installations, self.project_id, self.fid
+        ))
+        .json(API, &request)?
+        .header("Authorization", format!("FIS_v2 {}", self.refresh_token))
+        .header("x-goog-api-key", &self.api_key)
+        .send(http, API)
+        .await?;
+
+        let response: AuthTokenResponse = check_status(API, response)?.json(API)?;
+
+        self.auth_token = response.into_token();
+        Ok(&self.auth_token)
+    }
+
+    /// the current auth token, refreshed first if it is about to expire
+    pub async fn auth_token(
+        &mut self,
+        http: &dyn HttpTransport,
+        endpoints: &Endpoints,
+    ) -> Result<&InstallationAuthToken, Error> {
+        if self.auth_token.is_expiring() {
+            self.generate_auth_token(http, endpoints).await?;
+        }
+
+        Ok(&self.auth_token)
+    }
+
+    /// deletes the installation, its FID and tokens stop working
+    pub async fn delete(
+        &self,
+        http: &dyn HttpTransport,
+        endpoints: &Endpoints,
+    ) -> Result<(), Error> {
+        const API: &str = "Firebase installation deletion";
 
-        let response: InstallationResponse =
-            response.json().await.map_err(|e| Error::Response(API, e))?;
+        let response = HttpRequest::delete(format!(
+            "{}/projects/{}/installations/{}",
+            endpoints.installations, self.project_id, self.fid
+        ))
+        .header("Authorization", format!("FIS_v2 {}", self.refresh_token))
+        .header("x-goog-api-key", &self.api_key)
+        .send(http, API)
+        .await?;
 
-        Ok(response.auth_token)
+        check_status(API, response)?;
+        Ok(())
     }
 }
 
@@ -1,11 +1,14 @@
+#[allow(clippy::all)]
 pub mod contract {
     include!(concat!(env!("OUT_DIR"), "/checkin_proto.rs"));
 }
 
-use crate::Error;
+use crate::error::{check_status, time_limit};
+use crate::{Endpoints, Error, HttpRequest, HttpTransport};
 use prost::bytes::BufMut;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
+use std::time::Duration;
 use tokio_rustls::rustls::pki_types::ServerName;
 
 fn require_some<T>(value: Option<T>, reason: &'static str) -> Result<T, Error> {
@@ -15,8 +18,40 @@ fn require_some<T>(value: Option<T>, reason: &'static str) -> Result<T, Error> {
     }
 }
 
-const CHECKIN_URL: &str = "https://android.clients.google.com/checkin";
-const REGISTER_URL: &str = "https://android.clients.google.com/c2dm/register3";
+/// The Chrome build this crate poses as towards the check-in and MCS services
+#[derive(Clone, Debug)]
+pub struct ClientIdentity {
+    pub chrome_version: String,
+
+    /// 1 Windows, 2 Mac, 3 Linux, 4 Chrome OS, 5 iOS, 6 Android
+    pub platform: i32,
+
+    /// 1 stable, 2 beta, 3 dev, 4 canary, 5 unknown
+    pub channel: i32,
+
+    /// Device type of the check-in: 1 Android, 2 iOS, 3 Chrome browser, 4 Chrome OS
+    pub checkin_type: i32,
+    pub user_serial_number: i32,
+}
+
+impl Default for ClientIdentity {
+    fn default() -> Self {
+        Self {
+            chrome_version: "141.0.7390.0".into(),
+            platform: 2,
+            channel: 1,
+            checkin_type: 3,
+            user_serial_number: 0,
+        }
+    }
+}
+
+impl ClientIdentity {
+    /// the ID the MCS login names the client with
+    fn login_id(&self) -> String {
+        format!("chrome-{}", self.chrome_version)
+    }
+}
 
 // Normal JSON serialization will lose precision and change the number, so we must
 // force the i64/u64 to serialize to string.
@@ -32,7 +67,9 @@ pub struct Session {
 
 impl Session {
     async fn request(
-        http: &reqwest::Client,
+        http: &dyn HttpTransport,
+        endpoints: &Endpoints,
+        identity: &ClientIdentity,
         android_id: Option<i64>,
         security_token: Option<u64>,
     ) -> Result<Self, Error> {
@@ -42,13 +79,13 @@ impl Session {
             version: Some(3),
             id: android_id,
             security_token,
-            user_serial_number: Some(0),
+            user_serial_number: Some(identity.user_serial_number),
             checkin: contract::AndroidCheckinProto {
-                r#type: Some(3),
+                r#type: Some(identity.checkin_type),
                 chrome_build: Some(contract::ChromeBuildProto {
-                    platform: Some(2),
-                    channel: Some(1),
-                    chrome_version: Some(String::from("63.0.3234.0")),
+                    platform: Some(identity.platform),
+                    channel: Some(identity.channel),
+                    chrome_version: Some(identity.chrome_version.clone()),
                 }),
                 ..Default::default()
             },
@@ -57,19 +94,14 @@ impl Session {
 
         const API_NAME: &str = "GCM checkin";
 
-        let response = http
-            .post(CHECKIN_URL)
+        let response = HttpRequest::post(&endpoints.checkin)
+            .header("Content-Type", "application/x-protobuf")
             .body(request.encode_to_vec())
-            .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
-            .send()
-            .await
-            .map_err(|e| Error::Request(API_NAME, e))?;
+            .send(http, API_NAME)
+            .await?;
+        let response = check_status(API_NAME, response)?;
 
-        let response_bytes = response
-            .bytes()
-            .await
-            .map_err(|e| Error::Response(API_NAME, e))?;
-        let response = contract::AndroidCheckinResponse::decode(response_bytes)
+        let response = contract::AndroidCheckinResponse::decode(response.body.as_slice())
             .map_err(|e| Error::ProtobufDecode("android checkin response", e))?;
 
         let android_id = require_some(response.android_id, "response is missing android id")?;
@@ -91,44 +123,59 @@ impl Session {
     }
 
     /// check in to the device registration service, possibly obtaining a new security token
-    pub async fn checkin(&self, http: &reqwest::Client) -> Result<CheckedSession, Error> {
-        let r = Self::request(http, Some(self.android_id), Some(self.security_token)).await?;
-        Ok(CheckedSession(r))
+    pub async fn checkin(
+        &self,
+        http: &dyn HttpTransport,
+        endpoints: &Endpoints,
+        identity: &ClientIdentity,
+    ) -> Result<CheckedSession, Error> {
+        let r = Self::request(
+            http,
+            endpoints,
+            identity,
+            Some(self.android_id),
+            Some(self.security_token),
+        )
+        .await?;
+        Ok(CheckedSession(r, identity.clone()))
     }
 
     /// check in to the device registration service for the first time
     pub fn create<'a>(
-        http: &'a reqwest::Client,
+        http: &'a dyn HttpTransport,
+        endpoints: &'a Endpoints,
+        identity: &'a ClientIdentity,
     ) -> impl std::future::Future<Output = Result<Self, Error>> + 'a {
-        Self::request(http, None, None)
+        Self::request(http, endpoints, identity, None, None)
     }
 
-    pub async fn request_token(&self, app_id: &str) -> Result<String, Error> {
+    pub async fn request_token(
+        &self,
+        http: &dyn HttpTransport,
+        endpoints: &Endpoints,
+        app_id: &str,
+    ) -> Result<String, Error> {
         /// Server key in URL-safe base64
         const SERVER_KEY: &str =
             "BDOU99-h67HcA6JeFXHbSNMu7e2yNNu3RzoMj8TM4W88jITfq7ZmPvIM1Iv-4_l2LxQcYwhqby2xGpWwzjfAnG4";
 
         let android_id = self.android_id.to_string();
         let auth_header = format!("AidLogin {}:{}", &android_id, &self.security_token);
-        let mut params = std::collections::HashMap::with_capacity(4);
-        params.insert("app", "org.chromium.linux");
-        params.insert("X-subtype", app_id);
-        params.insert("device", &android_id);
-        params.insert("sender", SERVER_KEY);
+        let params = [
+            ("app", "org.chromium.linux"),
+            ("X-subtype", app_id),
+            ("device", &android_id),
+            ("sender", SERVER_KEY),
+        ];
 
         const API_NAME: &str = "GCM registration";
-        let result = reqwest::Client::new()
-            .post(REGISTER_URL)
+        let result = HttpRequest::post(&endpoints.register)
             .form(&params)
-            .header(reqwest::header::AUTHORIZATION, auth_header)
-            .send()
-            .await
-            .map_err(|e| Error::Request(API_NAME, e))?;
+            .header("Authorization", auth_header)
+            .send(http, API_NAME)
+            .await?;
 
-        let response_text = result
-            .text()
-            .await
-            .map_err(|e| Error::Response(API_NAME, e))?;
+        let response_text = check_status(API_NAME, result)?.text();
 
         const ERR_EOF: Error = Error::DependencyFailure(API_NAME, "malformed response");
 
@@ -149,25 +196,107 @@ impl Session {
             None => Err(ERR_EOF),
         }
     }
+
+    /// Unregisters the GCM token issued for `app_id` (`register3` with `delete=true`), so
+    /// messages for it are no longer routed to this device.
+    pub async fn delete_token(
+        &self,
+        http: &dyn HttpTransport,
+        endpoints: &Endpoints,
+        app_id: &str,
+    ) -> Result<(), Error> {
+        let android_id = self.android_id.to_string();
+        let auth_header = format!("AidLogin {}:{}", &android_id, &self.security_token);
+        let params = [
+            ("app", "org.chromium.linux"),
+            ("X-subtype", app_id),
+            ("device", &android_id),
+            ("delete", "true"),
+        ];
+
+        const API_NAME: &str = "GCM unregistration";
+        let result = HttpRequest::post(&endpoints.register)
+            .form(&params)
+            .header("Authorization", auth_header)
+            .send(http, API_NAME)
+            .await?;
+
+        let response_text = check_status(API_NAME, result)?.text();
+
+        match response_text.split_once('=') {
+            Some(("deleted", _)) => Ok(()),
+            Some(("Error", reason)) => Err(Error::DependencyRejection(API_NAME, reason.into())),
+            _ => Err(Error::DependencyFailure(API_NAME, "malformed response")),
+        }
+    }
 }
 
-fn new_tls_initiator() -> tokio_rustls::TlsConnector {
-    let root_store = tokio_rustls::rustls::RootCertStore {
-        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
-    };
+/// How the connection to the MCS server is set up
+#[derive(Clone)]
+pub struct ConnectionOptions {
+    /// Complete TLS settings for the MCS connection, the other TLS options are ignored when set
+    pub tls_config: Option<std::sync::Arc<rustls::ClientConfig>>,
 
-    let config = tokio_rustls::rustls::ClientConfig::builder()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
+    /// where the trust anchors for the server certificate come from
+    pub roots: crate::TrustRoots,
 
-    tokio_rustls::TlsConnector::from(std::sync::Arc::new(config))
+    /// further trust anchors, such as the CA of a TLS inspecting proxy or of a local test server
+    pub extra_roots: Vec<rustls::pki_types::CertificateDer<'static>>,
+
+    /// SHA-256 digests of SubjectPublicKeyInfo, see [`public_key_pin`](Self::public_key_pin).
+    /// When given, the verified chain from the server certificate to a trust anchor must carry
+    /// one of these keys on top of passing the usual checks.
+    pub pinned_public_keys: Vec<[u8; 32]>,
+
+    /// tunnel the MCS connection through this proxy
+    pub proxy: Option<crate::Proxy>,
+
+    /// time for name resolution and the TCP connection, through the proxy when there is one
+    pub connect_timeout: Option<Duration>,
+
+    /// time for the TLS handshake once connected
+    pub tls_handshake_timeout: Option<Duration>,
+
+    /// time the server gets to answer the login request
+    pub login_timeout: Option<Duration>,
 }
 
-pub struct CheckedSession(Session);
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            tls_config: None,
+            roots: crate::TrustRoots::default(),
+            extra_roots: Vec::new(),
+            pinned_public_keys: Vec::new(),
+            proxy: None,
+            connect_timeout: Some(Duration::from_secs(10)),
+            tls_handshake_timeout: Some(Duration::from_secs(10)),
+            login_timeout: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+impl ConnectionOptions {
+    /// Builds the TLS settings these options describe.
+    ///
+    /// Connecting does this on every attempt; store the result in `tls_config` to build it once.
+    pub fn client_config(&self) -> Result<std::sync::Arc<rustls::ClientConfig>, Error> {
+        crate::tls::client_config(self)
+    }
+
+    /// the pin of a DER certificate's public key, for `pinned_public_keys`
+    pub fn public_key_pin(certificate: &[u8]) -> Result<[u8; 32], Error> {
+        crate::tls::public_key_pin(certificate)
+    }
+}
+
+/// A session that has just checked in, with the identity it checked in as
+pub struct CheckedSession(Session, ClientIdentity);
 
 impl CheckedSession {
     const MCS_VERSION: u8 = 41;
     const LOGIN_REQUEST_TAG: u8 = 2;
+    const LOGIN_RESPONSE_TAG: u8 = 3;
 
     pub fn changed(&self, from: &Session) -> bool {
         self.0.security_token != from.security_token || self.0.android_id != from.android_id
@@ -182,7 +311,7 @@ impl CheckedSession {
             adaptive_heartbeat: Some(false),
             auth_service: Some(2),
             auth_token: self.0.security_token.to_string(),
-            id: "chrome-63.0.3234.0".into(),
+            id: self.1.login_id(),
             domain: "mcs.android.com".into(),
             device_id: Some(format!("android-{:x}", self.0.android_id)),
             network_type: Some(1),
@@ -199,24 +328,86 @@ impl CheckedSession {
     }
 
     async fn try_connect(
+        endpoints: &Endpoints,
+        options: &ConnectionOptions,
         domain: ServerName<'static>,
         login_bytes: &[u8],
-    ) -> Result<Connection, tokio::io::Error> {
+    ) -> Result<Connection, Error> {
+        let stream = time_limit("MCS connect", options.connect_timeout, async {
+            match options.proxy {
+                Some(ref proxy) => proxy.connect(&endpoints.mcs_host, endpoints.mcs_port).await,
+                None => {
+                    let address = (endpoints.mcs_host.as_str(), endpoints.mcs_port);
+                    tokio::net::TcpStream::connect(address)
+                        .await
+                        .map_err(Error::Socket)
+                }
+            }
+        })
+        .await?;
+        let tls = tokio_rustls::TlsConnector::from(options.client_config()?);
+        let stream = time_limit("MCS TLS handshake", options.tls_handshake_timeout, async {
+            tls.connect(domain, stream).await.map_err(Error::Socket)
+        })
+        .await?;
+
+        time_limit(
+            "MCS login",
+            options.login_timeout,
+            Self::login(stream, login_bytes),
+        )
+        .await
+    }
+
+    /// sends the login request and reads the response
+    async fn login(
+        mut stream: tokio_rustls::client::TlsStream<tokio::net::TcpStream>,
+        login_bytes: &[u8],
+    ) -> Result<Connection, Error> {
+        use prost::Message;
         use tokio::io::{AsyncReadExt, AsyncWriteExt};
-        let stream = tokio::net::TcpStream::connect("mtalk.google.com:5228").await?;
-        let tls = new_tls_initiator();
-        let mut stream = tls.connect(domain, stream).await?;
 
-        stream.write_all(login_bytes).await?;
+        stream.write_all(login_bytes).await.map_err(Error::Socket)?;
 
         // Read the version
-        stream.read_i8().await?;
+        stream.read_i8().await.map_err(Error::Socket)?;
+
+        let tag = stream.read_u8().await.map_err(Error::Socket)?;
+        if tag != Self::LOGIN_RESPONSE_TAG {
+            return Err(Error::DependencyFailure(
+                "MCS login",
+                "responded with something other than a login response",
+            ));
+        }
 
-        Ok(Connection(stream))
+        let size = read_varint(&mut stream).await.map_err(Error::Socket)?;
+        let mut response_bytes = vec![0; size];
+        stream
+            .read_exact(&mut response_bytes)
+            .await
+            .map_err(Error::Socket)?;
+
+        let login_response = crate::mcs::LoginResponse::decode(response_bytes.as_slice())
+            .map_err(|e| Error::ProtobufDecode("MCS login response", e))?;
+
+        if let Some(error) = login_response.error {
+            return Err(Error::LoginRejected {
+                code: error.code,
+                message: error.message,
+                kind: error.r#type,
+            });
+        }
+
+        Ok(Connection {
+            stream,
+            login_response,
+        })
     }
 
     pub async fn new_connection(
         &self,
+        endpoints: &Endpoints,
+        options: &ConnectionOptions,
         received_persistent_id: Vec<String>,
     ) -> Result<Connection, Error> {
         use prost::Message;
@@ -228,7 +419,7 @@ impl CheckedSession {
         const ERR_RESOLVE: Error =
             Error::DependencyFailure("name resolution", "unable to resolve google talk host name");
 
-        let domain = ServerName::try_from("mtalk.google.com").or(Err(ERR_RESOLVE))?;
+        let domain = ServerName::try_from(endpoints.mcs_host.clone()).or(Err(ERR_RESOLVE))?;
 
         let login_request = self.new_mcs_login_request(received_persistent_id);
 
@@ -239,9 +430,7 @@ impl CheckedSession {
             .encode_length_delimited(&mut login_bytes)
             .expect("login request encoding failure");
 
-        Self::try_connect(domain.clone(), &login_bytes)
-            .await
-            .map_err(Error::Socket)
+        Self::try_connect(endpoints, options, domain, &login_bytes).await
     }
 }
 
@@ -253,18 +442,50 @@ impl std::ops::Deref for CheckedSession {
     }
 }
 
-pub struct Connection(pub(crate) tokio_rustls::client::TlsStream<tokio::net::TcpStream>);
+/// reads a protobuf varint, one byte at a time
+pub(crate) async fn read_varint<R>(reader: &mut R) -> Result<usize, tokio::io::Error>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+
+    let mut result = 0;
+    for shift in (0..usize::BITS).step_by(7) {
+        let byte = reader.read_u8().await?;
+        result |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+
+    Err(tokio::io::Error::new(
+        tokio::io::ErrorKind::InvalidData,
+        "varint is too long",
+    ))
+}
+
+pub struct Connection {
+    pub(crate) stream: tokio_rustls::client::TlsStream<tokio::net::TcpStream>,
+    login_response: crate::mcs::LoginResponse,
+}
+
+impl Connection {
+    /// the server's answer to our login: stream id, server timestamp, heartbeat config and settings
+    pub fn login_response(&self) -> &crate::mcs::LoginResponse {
+        &self.login_response
+    }
+}
 
 impl std::ops::Deref for Connection {
     type Target = tokio_rustls::client::TlsStream<tokio::net::TcpStream>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.stream
     }
 }
 
 impl std::ops::DerefMut for Connection {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.stream
     }
 }
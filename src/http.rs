@@ -0,0 +1,280 @@
+use crate::error::time_limit;
+use crate::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// Time an HTTP call gets by default, see [`HttpTransport::timeout`]
+pub const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Future returned by [`HttpTransport::send`]
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Sends the HTTP requests of the check-in, registration and push calls.
+///
+/// `reqwest::Client` implements it when the `reqwest` feature is on, which it is by default.
+/// Implement it to go through another HTTP stack, or to answer from memory in tests.
+pub trait HttpTransport: Send + Sync {
+    /// Sends the request and reads the whole response.
+    ///
+    /// Error statuses are responses like any other; only failing to get a response is an error.
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, TransportError>>;
+
+    /// How long a call may take, sending and reading included, before it fails with
+    /// [`Error::Timeout`]. `None` waits forever.
+    fn timeout(&self) -> Option<Duration> {
+        Some(DEFAULT_HTTP_TIMEOUT)
+    }
+}
+
+impl<T: HttpTransport + ?Sized> HttpTransport for std::sync::Arc<T> {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, TransportError>> {
+        (**self).send(request)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        (**self).timeout()
+    }
+}
+
+/// A transport with another time limit per call than [`DEFAULT_HTTP_TIMEOUT`]
+pub struct WithTimeout<T> {
+    transport: T,
+    timeout: Option<Duration>,
+}
+
+impl<T: HttpTransport> WithTimeout<T> {
+    /// `transport` with calls limited to `timeout`, or not limited at all with `None`
+    pub fn new(transport: T, timeout: Option<Duration>) -> Self {
+        Self { transport, timeout }
+    }
+}
+
+impl<T: HttpTransport> HttpTransport for WithTimeout<T> {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, TransportError>> {
+        self.transport.send(request)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HttpMethod {
+    Get,
+    Post,
+    Delete,
+}
+
+impl HttpMethod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Get => "GET",
+            Self::Post => "POST",
+            Self::Delete => "DELETE",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub method: HttpMethod,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn new(method: HttpMethod, url: impl Into<String>) -> Self {
+        Self {
+            method,
+            url: url.into(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn get(url: impl Into<String>) -> Self {
+        Self::new(HttpMethod::Get, url)
+    }
+
+    pub fn post(url: impl Into<String>) -> Self {
+        Self::new(HttpMethod::Post, url)
+    }
+
+    pub fn delete(url: impl Into<String>) -> Self {
+        Self::new(HttpMethod::Delete, url)
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// the value of the first header named `name`, ignoring case
+    pub fn header_value(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// a JSON body, with its content type
+    pub(crate) fn json(
+        self,
+        kind: &'static str,
+        value: &impl serde::Serialize,
+    ) -> Result<Self, Error> {
+        let body = serde_json::to_vec(value).map_err(|e| Error::Json(kind, e))?;
+        Ok(self.header("Content-Type", "application/json").body(body))
+    }
+
+    /// a URL encoded form body, with its content type
+    pub(crate) fn form(self, fields: &[(&str, &str)]) -> Self {
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(fields)
+            .finish();
+        self.header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+    }
+
+    /// sends the request within the transport's time limit, blaming a failure on `api`
+    pub(crate) async fn send(
+        self,
+        http: &dyn HttpTransport,
+        api: &'static str,
+    ) -> Result<HttpResponse, Error> {
+        time_limit(api, http.timeout(), async {
+            http.send(self).await.map_err(|e| Error::Request(api, e))
+        })
+        .await
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// the value of the first header named `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// the body as text, invalid UTF-8 replaced
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub(crate) fn json<T: serde::de::DeserializeOwned>(
+        &self,
+        kind: &'static str,
+    ) -> Result<T, Error> {
+        serde_json::from_slice(&self.body).map_err(|e| Error::Json(kind, e))
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// An [`HttpTransport`] failed to get a response
+#[derive(Debug)]
+pub struct TransportError {
+    source: Box<dyn std::error::Error + Send + Sync>,
+    retryable: bool,
+}
+
+impl TransportError {
+    /// a failure that may pass, such as a refused connection or a timeout
+    pub fn transient(source: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self {
+            source: source.into(),
+            retryable: true,
+        }
+    }
+
+    /// a failure that repeats when the request is sent again, such as an invalid URL
+    pub fn permanent(source: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self {
+            source: source.into(),
+            retryable: false,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.retryable
+    }
+}
+
+impl std::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl std::error::Error for TransportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl HttpTransport for reqwest::Client {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, TransportError>> {
+        Box::pin(async move {
+            let method = match request.method {
+                HttpMethod::Get => reqwest::Method::GET,
+                HttpMethod::Post => reqwest::Method::POST,
+                HttpMethod::Delete => reqwest::Method::DELETE,
+            };
+
+            let mut builder = self.request(method, &request.url);
+            for (name, value) in request.headers {
+                builder = builder.header(name, value);
+            }
+            if !request.body.is_empty() {
+                builder = builder.body(request.body);
+            }
+
+            let response = builder.send().await.map_err(reqwest_error)?;
+            let status = response.status().as_u16();
+            let headers = response
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect();
+            let body = response.bytes().await.map_err(reqwest_error)?.to_vec();
+
+            Ok(HttpResponse {
+                status,
+                headers,
+                body,
+            })
+        })
+    }
+}
+
+#[cfg(feature = "reqwest")]
+fn reqwest_error(e: reqwest::Error) -> TransportError {
+    if e.is_builder() {
+        TransportError::permanent(e)
+    } else {
+        TransportError::transient(e)
+    }
+}
@@ -1,25 +1,76 @@
-mod mcs {
+#[allow(clippy::all)]
+pub mod mcs {
     include!(concat!(env!("OUT_DIR"), "/mcs_proto.rs"));
 }
 
+mod encrypt;
+mod endpoints;
 mod error;
 mod fcm;
 mod firebase;
 mod gcm;
+mod http;
+mod listener;
+mod proxy;
 mod push;
 mod register;
+mod retry;
+mod seal;
+mod sender;
+mod store;
+mod tls;
+#[cfg(feature = "testing")]
+pub mod testing;
 
+pub use encrypt::EncryptedPayload;
+pub use endpoints::Endpoints;
 pub use error::Error;
 pub use fcm::WebPushKeys;
+pub use firebase::Installation;
+pub use firebase::InstallationAuthToken;
+pub use gcm::ClientIdentity;
+pub use gcm::Connection;
+pub use gcm::ConnectionOptions;
 pub use gcm::Session;
+pub use http::BoxFuture;
+pub use http::HttpMethod;
+pub use http::HttpRequest;
+pub use http::HttpResponse;
+pub use http::HttpTransport;
+pub use http::TransportError;
+pub use http::WithTimeout;
+pub use http::DEFAULT_HTTP_TIMEOUT;
+pub use listener::Backoff;
+pub use listener::PushListener;
+pub use proxy::Proxy;
+pub use proxy::ProxyKind;
 pub use push::new_heartbeat_ack;
+pub use push::new_heartbeat_ping;
+pub use push::new_selective_ack;
+pub use push::ContentEncoding;
 pub use push::DataMessage;
 pub use push::Message;
 pub use push::MessageStream;
 pub use push::MessageTag;
 pub use register::register;
+pub use register::register_app;
+pub use register::register_app_with_retry;
+pub use register::register_with_retry;
 pub use register::Registration;
+pub use register::UnregisterReport;
+pub use retry::RetryPolicy;
+pub use seal::SealedRegistration;
+pub use seal::SealingKey;
+pub use sender::PushOptions;
+pub use sender::PushSender;
+pub use sender::Subscription;
+pub use sender::Urgency;
+pub use sender::VapidKeys;
+pub use store::FileRegistrationStore;
+pub use store::RegistrationStore;
+pub use store::StoredRegistration;
+pub use tls::TrustRoots;
 
 // C API модуль включается только при feature ffi
 #[cfg(feature = "ffi")]
-pub mod c_api;
\ No newline at end of file
+pub mod c_api;
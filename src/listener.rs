@@ -0,0 +1,589 @@
+use crate::push::{DataMessage, Message, MessageStream};
+use crate::{
+    ClientIdentity, ConnectionOptions, Endpoints, Error, HttpTransport, Registration,
+    RegistrationStore, StoredRegistration,
+};
+use bytes::BytesMut;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+type Stream = MessageStream<tokio_rustls::client::TlsStream<tokio::net::TcpStream>>;
+
+/// Heartbeat interval used when the login response doesn't suggest one
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Time the push service gets to answer our heartbeat before the connection is considered stale
+const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Hands the listener's state to its store, one save at a time
+struct StoreWriter {
+    store: Mutex<Box<dyn RegistrationStore>>,
+
+    /// the newest state not saved yet, saves that fall behind skip straight to it
+    pending: Mutex<Option<StoredRegistration>>,
+}
+
+impl StoreWriter {
+    fn save_pending(&self) -> Result<(), Error> {
+        let store = self.store.lock().unwrap();
+        let Some(state) = self.pending.lock().unwrap().take() else {
+            return Ok(());
+        };
+
+        store.save(&state).inspect_err(|_| {
+            // keep it for the next attempt, unless a newer state came in meanwhile
+            self.pending.lock().unwrap().get_or_insert(state);
+        })
+    }
+
+    fn save_pending_logged(&self) {
+        if let Err(e) = self.save_pending() {
+            log::warn!("Failed to save the registration: {e}");
+        }
+    }
+}
+
+/// Delay schedule applied between reconnection attempts
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    /// Delay before reconnecting after a connection was lost
+    pub initial: Duration,
+
+    /// Upper bound for the delay between consecutive failed attempts
+    pub max: Duration,
+
+    /// Factor applied to the delay after every consecutive failed attempt
+    pub multiplier: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(5),
+            max: Duration::from_secs(300),
+            multiplier: 2,
+        }
+    }
+}
+
+impl Backoff {
+    fn delay(&self, failures: u32) -> Duration {
+        let factor = self.multiplier.saturating_pow(failures);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Keeps a connection to the push service open and yields the messages that arrive on it.
+///
+/// The listener checks in, connects, answers heartbeats and reconnects when the connection is
+/// lost. Every received message is acknowledged over the live connection; persistent IDs the
+/// push service has not confirmed yet are kept and sent again on the next login.
+pub struct PushListener {
+    http: Arc<dyn HttpTransport>,
+    endpoints: Endpoints,
+    connection_options: ConnectionOptions,
+    identity: ClientIdentity,
+    registration: Registration,
+    apps: Vec<Registration>,
+    received_persistent_ids: Vec<String>,
+    pending_acks: HashMap<i32, String>,
+    store: Option<Arc<StoreWriter>>,
+    backoff: Backoff,
+    heartbeat_interval: Option<Duration>,
+    heartbeat_timeout: Duration,
+    suggested_heartbeat_interval: Option<Duration>,
+    stream: Option<Stream>,
+    failures: Option<u32>,
+    next_heartbeat: Instant,
+    heartbeat_deadline: Option<Instant>,
+}
+
+impl PushListener {
+    /// a listener that sends its check-in calls with a default `reqwest::Client`
+    #[cfg(feature = "reqwest")]
+    pub fn new(registration: Registration, received_persistent_ids: Vec<String>) -> Self {
+        Self::new_with_transport(
+            registration,
+            received_persistent_ids,
+            reqwest::Client::new(),
+        )
+    }
+
+    /// a listener that sends its check-in calls through `http`, the way to create one without the
+    /// `reqwest` feature
+    pub fn new_with_transport(
+        registration: Registration,
+        received_persistent_ids: Vec<String>,
+        http: impl HttpTransport + 'static,
+    ) -> Self {
+        Self {
+            http: Arc::new(http),
+            endpoints: Endpoints::default(),
+            connection_options: ConnectionOptions::default(),
+            identity: ClientIdentity::default(),
+            registration,
+            apps: Vec::new(),
+            received_persistent_ids,
+            pending_acks: HashMap::new(),
+            store: None,
+            backoff: Backoff::default(),
+            heartbeat_interval: None,
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+            suggested_heartbeat_interval: None,
+            stream: None,
+            failures: None,
+            next_heartbeat: Instant::now(),
+            heartbeat_deadline: None,
+        }
+    }
+
+    /// Restores a listener from the state saved in `store` and keeps saving to it, see
+    /// [`with_store`](Self::with_store).
+    ///
+    /// Returns `None` when the store is empty, register and create the listener with `new` then.
+    #[cfg(feature = "reqwest")]
+    pub fn from_store(store: impl RegistrationStore + 'static) -> Result<Option<Self>, Error> {
+        Self::from_store_with_transport(store, reqwest::Client::new())
+    }
+
+    /// like [`from_store`](Self::from_store), sending the check-in calls through `http`
+    pub fn from_store_with_transport(
+        store: impl RegistrationStore + 'static,
+        http: impl HttpTransport + 'static,
+    ) -> Result<Option<Self>, Error> {
+        let Some(state) = store.load()? else {
+            return Ok(None);
+        };
+
+        let mut listener =
+            Self::new_with_transport(state.registration, state.received_persistent_ids, http);
+        listener.apps = state.apps;
+        Ok(Some(listener.with_store(store)))
+    }
+
+    /// save the registration and the received persistent IDs to `store` whenever they change
+    pub fn with_store(mut self, store: impl RegistrationStore + 'static) -> Self {
+        self.store = Some(Arc::new(StoreWriter {
+            store: Mutex::new(Box::new(store)),
+            pending: Mutex::new(None),
+        }));
+        self.persist();
+        self
+    }
+
+    /// Also receive the messages of `registration`, an app registered on this listener's GCM
+    /// session with [`register_app`](crate::register_app).
+    ///
+    /// The messages arrive over the same connection, [`DataMessage::app_id`] tells which app they
+    /// are for.
+    pub fn with_app(mut self, registration: Registration) -> Self {
+        if registration.gcm.android_id != self.registration.gcm.android_id {
+            log::warn!("App was registered on another GCM session, its messages won't arrive");
+        } else if registration.gcm_app_id.is_empty() {
+            log::warn!("App was saved without its app ID, its messages can't be told apart");
+        }
+
+        self.apps
+            .retain(|app| app.gcm_app_id != registration.gcm_app_id);
+        if let Some(ref mut stream) = self.stream {
+            stream.add_app(&registration.gcm_app_id, &registration.keys);
+        }
+        self.apps.push(registration);
+        self.persist();
+        self
+    }
+
+    /// use the given client for the device check-in calls
+    #[cfg(feature = "reqwest")]
+    pub fn with_http_client(self, http: reqwest::Client) -> Self {
+        self.with_transport(http)
+    }
+
+    /// send the device check-in calls through `http`
+    pub fn with_transport(mut self, http: impl HttpTransport + 'static) -> Self {
+        self.http = Arc::new(http);
+        self
+    }
+
+    pub fn with_endpoints(mut self, endpoints: Endpoints) -> Self {
+        self.endpoints = endpoints;
+        self
+    }
+
+    pub fn with_connection_options(mut self, options: ConnectionOptions) -> Self {
+        self.connection_options = options;
+        self
+    }
+
+    /// check in and log in as this Chrome build, it should match the one the registration was made
+    /// with
+    pub fn with_client_identity(mut self, identity: ClientIdentity) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Send the check-in calls and the MCS connection through `proxy`.
+    ///
+    /// This replaces the HTTP transport and the proxy in the connection options.
+    #[cfg(feature = "reqwest")]
+    pub fn with_proxy(mut self, proxy: crate::Proxy) -> Result<Self, Error> {
+        self.http = Arc::new(proxy.http_client()?);
+        self.connection_options.proxy = Some(proxy);
+        Ok(self)
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// send our own heartbeat after this much silence, instead of the interval suggested by the
+    /// login response
+    pub fn with_heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = Some(interval);
+        self
+    }
+
+    /// how long to wait for the push service to answer our heartbeat before reconnecting
+    pub fn with_heartbeat_timeout(mut self, timeout: Duration) -> Self {
+        self.heartbeat_timeout = timeout;
+        self
+    }
+
+    /// the registration in use, its GCM session is updated if a check-in issues a new one
+    pub fn registration(&self) -> &Registration {
+        &self.registration
+    }
+
+    /// the apps added with [`with_app`](Self::with_app)
+    pub fn apps(&self) -> &[Registration] {
+        &self.apps
+    }
+
+    /// persistent IDs of received messages the push service hasn't confirmed yet, including the
+    /// ones passed in
+    pub fn received_persistent_ids(&self) -> &[String] {
+        &self.received_persistent_ids
+    }
+
+    /// Writes state still waiting for its background save to the store, blocking until it's done.
+    ///
+    /// Saves run on the blocking thread pool, which drops the ones that haven't started when the
+    /// runtime shuts down; call this before that. Dropping the listener also saves, but can't
+    /// report an error.
+    pub fn flush(&self) -> Result<(), Error> {
+        match self.store {
+            Some(ref writer) => writer.save_pending(),
+            None => Ok(()),
+        }
+    }
+
+    /// Waits for the next push message, connecting to the push service when needed.
+    ///
+    /// Messages without an encrypted payload are returned with an empty `body` and
+    /// [`encrypted`](DataMessage::encrypted) unset, their content is in `app_data`.
+    ///
+    /// Errors that break the connection, including [`Error::ConnectionStale`] when a heartbeat goes
+    /// unanswered, are returned after the connection has been dropped; the next call reconnects
+    /// once the backoff delay has passed. Errors about a single message leave the connection open.
+    /// A message that can't be decrypted is acknowledged like any other and reported as
+    /// [`Error::UndecodableMessage`], so it doesn't come back on the next login.
+    pub async fn next(&mut self) -> Result<DataMessage, Error> {
+        use tokio_stream::StreamExt;
+
+        loop {
+            self.connect_if_needed().await?;
+
+            if self.stream.as_ref().is_some_and(Stream::stream_ack_due) {
+                log::debug!("Confirming received frames");
+                self.send_with(Stream::stream_ack).await?;
+            }
+
+            let deadline = self.heartbeat_deadline.unwrap_or(self.next_heartbeat);
+            let Some(ref mut stream) = self.stream else {
+                continue;
+            };
+
+            let message = tokio::select! {
+                message = stream.next() => message,
+                _ = tokio::time::sleep_until(deadline) => {
+                    self.heartbeat().await?;
+                    continue;
+                }
+            };
+
+            if matches!(message, Some(Ok(_))) {
+                // any traffic proves the connection is alive
+                self.heartbeat_deadline = None;
+                self.next_heartbeat = Instant::now() + self.interval();
+                self.confirm_acknowledged();
+            }
+
+            match message {
+                Some(Ok(Message::Data(message) | Message::AppData(message))) => {
+                    if let Some(ref id) = message.persistent_id {
+                        self.received(id.clone()).await;
+                    }
+
+                    return Ok(message);
+                }
+                Some(Err(e @ Error::UndecodableMessage { .. })) => {
+                    // acknowledged all the same, it would fail again on every login otherwise
+                    if let Error::UndecodableMessage {
+                        persistent_id: Some(ref id),
+                        ..
+                    } = e
+                    {
+                        self.received(id.clone()).await;
+                    }
+
+                    return Err(e);
+                }
+                Some(Ok(Message::HeartbeatPing(_))) => {
+                    log::debug!("Answering heartbeat");
+                    self.send_with(Stream::heartbeat_ack).await?;
+                }
+                Some(Ok(Message::Iq(iq))) => self.handle_iq(iq),
+                Some(Ok(Message::StreamError(error))) => {
+                    log::warn!(
+                        "Push service stream error: {} {:?}",
+                        error.r#type,
+                        error.text
+                    );
+                }
+                Some(Ok(Message::Close)) => log::debug!("Push service is closing the connection"),
+                Some(Ok(Message::Other(tag, _))) => log::debug!("Ignoring message with tag {tag}"),
+                Some(Ok(_)) => {}
+                Some(Err(e @ Error::Socket(_))) => {
+                    self.stream = None;
+                    return Err(e);
+                }
+                Some(Err(e)) => return Err(e),
+                None => {
+                    log::debug!("Push service closed the connection");
+                    self.stream = None;
+                }
+            }
+        }
+    }
+
+    async fn connect_if_needed(&mut self) -> Result<(), Error> {
+        if self.stream.is_some() {
+            return Ok(());
+        }
+
+        if let Some(failures) = self.failures {
+            tokio::time::sleep(self.backoff.delay(failures)).await;
+        }
+
+        match self.connect().await {
+            Ok(stream) => {
+                self.failures = Some(0);
+                self.stream = Some(stream);
+                Ok(())
+            }
+            Err(e) => {
+                self.failures = Some(self.failures.map_or(0, |n| n.saturating_add(1)));
+                Err(e)
+            }
+        }
+    }
+
+    async fn connect(&mut self) -> Result<Stream, Error> {
+        log::debug!("Checking in to GCM");
+        let session = self
+            .registration
+            .gcm
+            .checkin(self.http.as_ref(), &self.endpoints, &self.identity)
+            .await?;
+        let session_changed = session.changed(&self.registration.gcm);
+        if session_changed {
+            self.registration.gcm = (*session).clone();
+            for app in &mut self.apps {
+                app.gcm = (*session).clone();
+            }
+        }
+
+        if self.connection_options.tls_config.is_none() {
+            // built once, the root store doesn't change between reconnects
+            self.connection_options.tls_config = Some(self.connection_options.client_config()?);
+        }
+
+        log::debug!("Connecting to the push service");
+        self.pending_acks.clear();
+        let login_persistent_ids = self.received_persistent_ids.clone();
+        let connection = session
+            .new_connection(
+                &self.endpoints,
+                &self.connection_options,
+                login_persistent_ids.clone(),
+            )
+            .await?;
+
+        // the login carried these IDs and was accepted, so the server has them now
+        if self.confirm(&login_persistent_ids) || session_changed {
+            self.persist();
+        }
+
+        self.suggested_heartbeat_interval = connection
+            .login_response()
+            .heartbeat_config
+            .as_ref()
+            .and_then(|config| config.interval_ms)
+            .filter(|ms| *ms > 0)
+            .map(|ms| Duration::from_millis(ms as u64));
+        self.heartbeat_deadline = None;
+        self.next_heartbeat = Instant::now() + self.interval();
+
+        let mut stream = MessageStream::wrap(connection, &self.registration.keys);
+        for app in &self.apps {
+            stream.add_app(&app.gcm_app_id, &app.keys);
+        }
+
+        Ok(stream)
+    }
+
+    fn interval(&self) -> Duration {
+        self.heartbeat_interval
+            .or(self.suggested_heartbeat_interval)
+            .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL)
+    }
+
+    async fn heartbeat(&mut self) -> Result<(), Error> {
+        if self.heartbeat_deadline.is_some() {
+            log::warn!("Push service did not answer our heartbeat, reconnecting");
+            self.stream = None;
+            return Err(Error::ConnectionStale);
+        }
+
+        log::debug!("Sending heartbeat");
+        self.send_with(Stream::heartbeat_ping).await?;
+        self.heartbeat_deadline = Some(Instant::now() + self.heartbeat_timeout);
+        Ok(())
+    }
+
+    /// records a received message and acknowledges it
+    async fn received(&mut self, persistent_id: String) {
+        self.received_persistent_ids.push(persistent_id.clone());
+        self.persist();
+        if let Err(e) = self.acknowledge(persistent_id.clone()).await {
+            // the ID is sent again on the next login
+            log::warn!("Failed to acknowledge message {persistent_id}: {e}");
+        }
+    }
+
+    async fn acknowledge(&mut self, persistent_id: String) -> Result<(), Error> {
+        let persistent_ids = vec![persistent_id.clone()];
+        self.send_with(|stream| stream.selective_ack(persistent_ids))
+            .await?;
+
+        if let Some(ref stream) = self.stream {
+            self.pending_acks.insert(stream.stream_id(), persistent_id);
+        }
+
+        Ok(())
+    }
+
+    fn handle_iq(&mut self, iq: crate::mcs::IqStanza) {
+        use crate::mcs::iq_stanza::IqType;
+
+        let persistent_id = iq
+            .id
+            .parse()
+            .ok()
+            .and_then(|stream_id| self.pending_acks.remove(&stream_id));
+        let Some(persistent_id) = persistent_id else {
+            log::debug!("Ignoring IQ stanza {:?}", iq.id);
+            return;
+        };
+
+        if iq.r#type() == IqType::Result {
+            if self.confirm(&[persistent_id]) {
+                self.persist();
+            }
+        } else {
+            log::warn!("Push service did not accept the ack for {persistent_id}");
+        }
+    }
+
+    /// confirms the acks sent in frames the server reported to have received
+    fn confirm_acknowledged(&mut self) {
+        let Some(ref stream) = self.stream else {
+            return;
+        };
+
+        let acknowledged = stream.acknowledged_stream_id();
+        let mut confirmed = Vec::new();
+        self.pending_acks.retain(|stream_id, persistent_id| {
+            if *stream_id > acknowledged {
+                return true;
+            }
+
+            confirmed.push(std::mem::take(persistent_id));
+            false
+        });
+
+        if self.confirm(&confirmed) {
+            self.persist();
+        }
+    }
+
+    /// forgets persistent IDs the server has, returns whether any were still kept
+    fn confirm(&mut self, persistent_ids: &[String]) -> bool {
+        let count = self.received_persistent_ids.len();
+        self.received_persistent_ids
+            .retain(|id| !persistent_ids.contains(id));
+        self.received_persistent_ids.len() != count
+    }
+
+    /// Saves the current state, on the blocking thread pool when called from within a runtime so
+    /// a slow store doesn't hold up the connection.
+    fn persist(&self) {
+        let Some(ref writer) = self.store else {
+            return;
+        };
+
+        *writer.pending.lock().unwrap() = Some(StoredRegistration {
+            registration: self.registration.clone(),
+            received_persistent_ids: self.received_persistent_ids.clone(),
+            apps: self.apps.clone(),
+        });
+
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                let writer = writer.clone();
+                runtime.spawn_blocking(move || writer.save_pending_logged());
+            }
+            Err(_) => writer.save_pending_logged(),
+        }
+    }
+
+    async fn send_with(
+        &mut self,
+        build: impl FnOnce(&mut Stream) -> BytesMut,
+    ) -> Result<(), Error> {
+        use tokio::io::AsyncWriteExt;
+
+        let Some(ref mut stream) = self.stream else {
+            return Ok(());
+        };
+
+        let bytes = build(stream);
+        if let Err(e) = stream.write_all(&bytes).await {
+            self.stream = None;
+            return Err(Error::Socket(e));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for PushListener {
+    fn drop(&mut self) {
+        if let Some(ref writer) = self.store {
+            writer.save_pending_logged();
+        }
+    }
+}
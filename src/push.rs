@@ -2,8 +2,10 @@ use crate::Error;
 use bytes::{Bytes, BytesMut};
 use ece::EcKeyComponents;
 use pin_project_lite::pin_project;
+use std::collections::HashMap;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
 
 #[allow(dead_code)]
 #[derive(PartialEq, Debug)]
@@ -32,7 +34,7 @@ impl TryFrom<u8> for MessageTag {
 
     fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
         if value < Self::NumProtoTypes as u8 {
-            Ok(unsafe { std::mem::transmute(value) })
+            Ok(unsafe { std::mem::transmute::<u8, MessageTag>(value) })
         } else {
             Err(value)
         }
@@ -40,78 +42,240 @@ impl TryFrom<u8> for MessageTag {
 }
 
 pub enum Message {
-    HeartbeatPing,
+    HeartbeatPing(crate::mcs::HeartbeatPing),
+    HeartbeatAck(crate::mcs::HeartbeatAck),
+    LoginResponse(crate::mcs::LoginResponse),
+    /// The server is closing the stream, nothing follows
+    Close,
+    Iq(crate::mcs::IqStanza),
     Data(DataMessage),
+    /// A data message without an encrypted payload, its content is in `app_data` and `body` is
+    /// empty
+    AppData(DataMessage),
+    StreamError(crate::mcs::StreamErrorStanza),
+    /// Frames whose tag is unknown or has no definition in `mcs.proto`
     Other(u8, Bytes),
 }
 
+/// Web push payload encryption schemes
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ContentEncoding {
+    /// RFC 8291, salt and sender key travel in the payload header
+    Aes128Gcm,
+    /// Legacy draft scheme, salt and sender key travel in the `encryption` and `crypto-key` fields
+    AesGcm,
+}
+
+impl ContentEncoding {
+    /// the `Content-Encoding` value naming this scheme
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Aes128Gcm => "aes128gcm",
+            Self::AesGcm => "aesgcm",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            v if v.eq_ignore_ascii_case("aes128gcm") => Some(Self::Aes128Gcm),
+            v if v.eq_ignore_ascii_case("aesgcm") => Some(Self::AesGcm),
+            _ => None,
+        }
+    }
+}
+
 pub struct DataMessage {
     pub body: Vec<u8>,
+
+    /// The message carried an encrypted payload, decrypted into `body`. Messages without one
+    /// ([`Message::AppData`]) have their content in `app_data`.
+    pub encrypted: bool,
     pub persistent_id: Option<String>,
-}
 
-impl DataMessage {
-    fn decode(eckey: &EcKeyComponents, auth_secret: &[u8], bytes: &[u8]) -> Result<Self, Error> {
-        use base64::engine::general_purpose::URL_SAFE;
-        use base64::Engine;
-        use ece::legacy::AesGcmEncryptedBlock;
-        use prost::Message;
+    /// Message ID assigned by the sender
+    pub id: Option<String>,
+
+    /// Sender ID
+    pub from: String,
+    pub category: String,
+    pub token: Option<String>,
+
+    /// App ID (`X-subtype`) the message is addressed to, see [`MessageStream::with_app`]
+    pub app_id: Option<String>,
 
-        let message = crate::mcs::DataMessageStanza::decode(bytes)
-            .map_err(|e| Error::ProtobufDecode("FCM data message", e))?;
+    /// Key/value pairs sent alongside the payload, including the encryption parameters
+    pub app_data: HashMap<String, String>,
+
+    /// How long the push service holds on to the message for delivery
+    pub ttl: Option<Duration>,
+
+    /// When the sender handed the message to the push service
+    pub sent: Option<SystemTime>,
+
+    /// How long the message was queued by the push service
+    pub queued: Option<Duration>,
+
+    /// The sender asked for the message to be acknowledged right away
+    pub immediate_ack: bool,
+}
 
-        let bytes = match message.raw_data {
+impl DataMessage {
+    fn decode(
+        eckey: &EcKeyComponents,
+        auth_secret: &[u8],
+        mut message: crate::mcs::DataMessageStanza,
+    ) -> Result<Self, Error> {
+        let bytes = match message.raw_data.take() {
             Some(v) => v,
             None => {
                 return Err(Error::EmptyPayload);
             }
         };
 
-        let mut kex: Vec<u8> = Vec::default();
-        let mut salt: Vec<u8> = Vec::default();
-        for field in message.app_data {
-            match field.key.as_str() {
-                "crypto-key" => {
-                    // crypto_key format: dh=abc...
-                    kex = URL_SAFE
-                        .decode(&field.value[3..])
-                        .map_err(|e| Error::Base64Decode("FCM message crypto-key", e))?;
-
-                    if !salt.is_empty() {
-                        break;
-                    }
-                }
-                "encryption" => {
-                    // encryption format: salt=abc...
-                    salt = URL_SAFE
-                        .decode(&field.value[5..])
-                        .map_err(|e| Error::Base64Decode("FCM message encryption params", e))?;
-
-                    if !kex.is_empty() {
-                        break;
-                    }
-                }
-                _ => {}
+        let content_encoding = message
+            .app_data
+            .iter()
+            .find(|field| field.key == "content-encoding");
+        let content_encoding = match content_encoding {
+            Some(field) => ContentEncoding::parse(&field.value)
+                .ok_or_else(|| Error::UnsupportedContentEncoding(field.value.clone()))?,
+            // legacy senders don't always say so, but their parameters give them away
+            None if message.app_data.iter().any(|f| f.key == "crypto-key") => {
+                ContentEncoding::AesGcm
             }
-        }
-
-        if kex.is_empty() {
-            return Err(Error::MissingCryptoMetadata("crypto-key"));
-        } else if salt.is_empty() {
-            return Err(Error::MissingCryptoMetadata("encryption"));
-        }
+            None => ContentEncoding::Aes128Gcm,
+        };
 
-        // The record size default is 4096 and doesn't seem to be overridden for FCM.
-        const RECORD_SIZE: u32 = 4096;
         const OPERATION: &str = "message decryption";
-        let block = AesGcmEncryptedBlock::new(&kex, &salt, RECORD_SIZE, bytes)
-            .map_err(|e| Error::Crypto(OPERATION, e))?;
-        let body = ece::legacy::decrypt_aesgcm(eckey, auth_secret, &block)
-            .map_err(|e| Error::Crypto(OPERATION, e))?;
+        let body = match content_encoding {
+            // salt and sender key are part of the payload header
+            ContentEncoding::Aes128Gcm => {
+                ece::decrypt(eckey, auth_secret, &bytes).map_err(|e| Error::Crypto(OPERATION, e))?
+            }
+            ContentEncoding::AesGcm => {
+                decrypt_aesgcm(eckey, auth_secret, &message.app_data, bytes)?
+            }
+        };
+
         Ok(Self {
+            encrypted: true,
+            ..Self::new(message, body)
+        })
+    }
+
+    fn new(message: crate::mcs::DataMessageStanza, body: Vec<u8>) -> Self {
+        let seconds = |value: Option<i32>| {
+            value
+                .and_then(|v| u64::try_from(v).ok())
+                .map(Duration::from_secs)
+        };
+
+        let app_id = app_id(&message).map(String::from);
+        Self {
             body,
+            encrypted: false,
             persistent_id: message.persistent_id,
-        })
+            id: message.id,
+            from: message.from,
+            category: message.category,
+            token: message.token,
+            app_id,
+            app_data: message
+                .app_data
+                .into_iter()
+                .map(|field| (field.key, field.value))
+                .collect(),
+            ttl: seconds(message.ttl),
+            sent: message
+                .sent
+                .and_then(|sent| u64::try_from(sent).ok())
+                .map(|sent| SystemTime::UNIX_EPOCH + Duration::from_secs(sent)),
+            queued: seconds(message.queued),
+            immediate_ack: message.immediate_ack.unwrap_or(false),
+        }
+    }
+}
+
+/// Package the push service files web push registrations under, the app is named by the
+/// `subtype` in the app data then
+const PACKAGE_NAME: &str = "org.chromium.linux";
+
+/// the app a data message is addressed to, as Chrome's GCM client works it out
+fn app_id(message: &crate::mcs::DataMessageStanza) -> Option<&str> {
+    if message.category != PACKAGE_NAME {
+        return Some(message.category.as_str()).filter(|category| !category.is_empty());
+    }
+
+    message
+        .app_data
+        .iter()
+        .find(|field| field.key == "subtype")
+        .map(|field| field.value.as_str())
+}
+
+fn decrypt_aesgcm(
+    eckey: &EcKeyComponents,
+    auth_secret: &[u8],
+    app_data: &[crate::mcs::AppData],
+    bytes: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    use base64::engine::general_purpose::URL_SAFE;
+    use base64::Engine;
+    use ece::legacy::AesGcmEncryptedBlock;
+
+    // crypto-key format: dh=abc...[;p256ecdsa=def...], encryption format: salt=abc...
+    let kex = header_parameter(app_data, "crypto-key", "dh")
+        .ok_or(Error::MissingCryptoMetadata("crypto-key"))?;
+    let kex = URL_SAFE
+        .decode(kex)
+        .map_err(|e| Error::Base64Decode("FCM message crypto-key", e))?;
+
+    let salt = header_parameter(app_data, "encryption", "salt")
+        .ok_or(Error::MissingCryptoMetadata("encryption"))?;
+    let salt = URL_SAFE
+        .decode(salt)
+        .map_err(|e| Error::Base64Decode("FCM message encryption params", e))?;
+
+    // The record size default is 4096 and doesn't seem to be overridden for FCM.
+    const RECORD_SIZE: u32 = 4096;
+    const OPERATION: &str = "message decryption";
+    let block = AesGcmEncryptedBlock::new(&kex, &salt, RECORD_SIZE, bytes)
+        .map_err(|e| Error::Crypto(OPERATION, e))?;
+    ece::legacy::decrypt_aesgcm(eckey, auth_secret, &block).map_err(|e| Error::Crypto(OPERATION, e))
+}
+
+/// the value of parameter `name` in the `key` header of a message, which holds `name=value` pairs
+/// separated by `;` or `,`
+fn header_parameter<'a>(
+    app_data: &'a [crate::mcs::AppData],
+    key: &str,
+    name: &str,
+) -> Option<&'a str> {
+    let field = app_data.iter().find(|field| field.key == key)?;
+    field
+        .value
+        .split([';', ','])
+        .filter_map(|parameter| parameter.trim().strip_prefix(name)?.strip_prefix('='))
+        .map(|value| value.trim_matches('"'))
+        .find(|value| !value.is_empty())
+}
+
+/// Keys that decrypt the messages for one app
+struct AppKeys {
+    /// `None` for the keys given to [`MessageStream::wrap`], which take every message not
+    /// addressed to another app
+    app_id: Option<String>,
+    eckey: EcKeyComponents,
+    auth_secret: Vec<u8>,
+}
+
+impl AppKeys {
+    fn new(app_id: Option<String>, keys: &crate::fcm::WebPushKeys) -> Self {
+        Self {
+            app_id,
+            eckey: EcKeyComponents::new(keys.private_key.clone(), keys.public_key.clone()),
+            auth_secret: keys.auth_secret.clone(),
+        }
     }
 }
 
@@ -119,27 +283,195 @@ pin_project! {
     pub struct MessageStream<T> {
         #[pin]
         inner: T,
-        eckey: EcKeyComponents,
-        auth_secret: Vec<u8>,
+        apps: Vec<AppKeys>,
         bytes_required: usize,
         receive_buffer: BytesMut,
+        stream_id: i32,
+        last_stream_id_received: i32,
+        last_stream_id_reported: i32,
+        acknowledged_stream_id: i32,
     }
 }
 
 impl MessageStream<tokio_rustls::client::TlsStream<tokio::net::TcpStream>> {
     pub fn wrap(connection: crate::gcm::Connection, keys: &crate::fcm::WebPushKeys) -> Self {
-        Self::new(connection.0, keys)
+        let mut stream = Self::new(connection.stream, keys);
+
+        // the login request and response were the first frames in either direction
+        stream.stream_id = 1;
+        stream.last_stream_id_received = 1;
+        stream.last_stream_id_reported = 1;
+        stream
     }
 }
 
 impl<T> MessageStream<T> {
-    fn new(inner: T, keys: &crate::fcm::WebPushKeys) -> Self {
+    pub(crate) fn new(inner: T, keys: &crate::fcm::WebPushKeys) -> Self {
         Self {
             inner,
-            eckey: EcKeyComponents::new(keys.private_key.clone(), keys.public_key.clone()),
-            auth_secret: keys.auth_secret.clone(),
+            apps: vec![AppKeys::new(None, keys)],
             bytes_required: 2,
             receive_buffer: BytesMut::with_capacity(1024),
+            stream_id: 0,
+            last_stream_id_received: 0,
+            last_stream_id_reported: 0,
+            acknowledged_stream_id: 0,
+        }
+    }
+
+    /// Decrypt the messages addressed to `app_id` with `keys`, so apps registered on the same GCM
+    /// session can share a connection, see [`register_app`](crate::register_app).
+    ///
+    /// Messages for other apps are decrypted with the keys the stream was created with.
+    pub fn with_app(mut self, app_id: impl Into<String>, keys: &crate::fcm::WebPushKeys) -> Self {
+        self.add_app(app_id, keys);
+        self
+    }
+
+    /// like [`with_app`](Self::with_app), for a stream that is already in use
+    pub fn add_app(&mut self, app_id: impl Into<String>, keys: &crate::fcm::WebPushKeys) {
+        let app_id = app_id.into();
+        self.apps.retain(|app| app.app_id.as_ref() != Some(&app_id));
+        self.apps.push(AppKeys::new(Some(app_id), keys));
+    }
+
+    /// keys for a message addressed to `app_id`
+    fn keys_for(&self, app_id: Option<&str>) -> &AppKeys {
+        app_id
+            .and_then(|app_id| {
+                self.apps
+                    .iter()
+                    .find(|app| app.app_id.as_deref() == Some(app_id))
+            })
+            .unwrap_or(&self.apps[0])
+    }
+
+    /// stream ID of the last frame built by this stream, i.e. the number of frames sent
+    pub fn stream_id(&self) -> i32 {
+        self.stream_id
+    }
+
+    /// number of frames received from the server
+    pub fn last_stream_id_received(&self) -> i32 {
+        self.last_stream_id_received
+    }
+
+    /// highest stream ID the server reported to have received from us
+    pub fn acknowledged_stream_id(&self) -> i32 {
+        self.acknowledged_stream_id
+    }
+
+    /// whether enough frames went unconfirmed that the server expects a [`Self::stream_ack`]
+    pub fn stream_ack_due(&self) -> bool {
+        self.last_stream_id_received - self.last_stream_id_reported >= UNACKED_FRAME_LIMIT
+    }
+
+    pub fn heartbeat_ping(&mut self) -> BytesMut {
+        let ping = crate::mcs::HeartbeatPing {
+            last_stream_id_received: Some(self.next_stream_id()),
+            ..Default::default()
+        };
+        encode_frame(MessageTag::HeartbeatPing, &ping)
+    }
+
+    pub fn heartbeat_ack(&mut self) -> BytesMut {
+        let ack = crate::mcs::HeartbeatAck {
+            last_stream_id_received: Some(self.next_stream_id()),
+            ..Default::default()
+        };
+        encode_frame(MessageTag::HeartbeatAck, &ack)
+    }
+
+    /// Acknowledge the given persistent IDs, they are confirmed once [`Self::acknowledged_stream_id`]
+    /// reaches the [`Self::stream_id`] of this frame
+    pub fn selective_ack(&mut self, persistent_ids: Vec<String>) -> BytesMut {
+        let last_stream_id_received = self.next_stream_id();
+        let iq = crate::mcs::IqStanza {
+            last_stream_id_received: Some(last_stream_id_received),
+            ..selective_ack_stanza(self.stream_id.to_string(), persistent_ids)
+        };
+        encode_frame(MessageTag::IqStanza, &iq)
+    }
+
+    /// Confirm every frame received so far
+    pub fn stream_ack(&mut self) -> BytesMut {
+        let iq = crate::mcs::IqStanza {
+            r#type: crate::mcs::iq_stanza::IqType::Set as i32,
+            extension: Some(crate::mcs::Extension {
+                id: STREAM_ACK_EXTENSION,
+                data: prost::Message::encode_to_vec(&crate::mcs::StreamAck {}),
+            }),
+            last_stream_id_received: Some(self.next_stream_id()),
+            ..Default::default()
+        };
+        encode_frame(MessageTag::IqStanza, &iq)
+    }
+
+    /// advances the outgoing stream ID, returns the last stream ID received to report with it
+    fn next_stream_id(&mut self) -> i32 {
+        self.stream_id = self.stream_id.wrapping_add(1);
+        self.last_stream_id_reported = self.last_stream_id_received;
+        self.last_stream_id_received
+    }
+
+    fn decode(&mut self, tag: u8, bytes: BytesMut) -> Result<Message, Error> {
+        fn decode<T: prost::Message + Default>(
+            kind: &'static str,
+            bytes: &[u8],
+        ) -> Result<T, Error> {
+            T::decode(bytes).map_err(|e| Error::ProtobufDecode(kind, e))
+        }
+
+        self.last_stream_id_received = self.last_stream_id_received.wrapping_add(1);
+
+        Ok(match MessageTag::try_from(tag) {
+            Ok(MessageTag::HeartbeatPing) => {
+                let ping: crate::mcs::HeartbeatPing = decode("heartbeat ping", &bytes)?;
+                self.server_received(ping.last_stream_id_received);
+                Message::HeartbeatPing(ping)
+            }
+            Ok(MessageTag::HeartbeatAck) => {
+                let ack: crate::mcs::HeartbeatAck = decode("heartbeat ack", &bytes)?;
+                self.server_received(ack.last_stream_id_received);
+                Message::HeartbeatAck(ack)
+            }
+            Ok(MessageTag::LoginResponse) => {
+                let response: crate::mcs::LoginResponse = decode("MCS login response", &bytes)?;
+                self.server_received(response.last_stream_id_received);
+                Message::LoginResponse(response)
+            }
+            Ok(MessageTag::Close) => Message::Close,
+            Ok(MessageTag::IqStanza) => {
+                let iq: crate::mcs::IqStanza = decode("IQ stanza", &bytes)?;
+                self.server_received(iq.last_stream_id_received);
+                Message::Iq(iq)
+            }
+            Ok(MessageTag::DataMessageStanza) => {
+                let stanza: crate::mcs::DataMessageStanza = decode("FCM data message", &bytes)?;
+                self.server_received(stanza.last_stream_id_received);
+                if stanza.raw_data.is_none() {
+                    Message::AppData(DataMessage::new(stanza, Vec::new()))
+                } else {
+                    let keys = self.keys_for(app_id(&stanza));
+                    let persistent_id = stanza.persistent_id.clone();
+                    let message = DataMessage::decode(&keys.eckey, &keys.auth_secret, stanza)
+                        .map_err(|e| Error::UndecodableMessage {
+                            persistent_id,
+                            error: Box::new(e),
+                        })?;
+                    Message::Data(message)
+                }
+            }
+            Ok(MessageTag::StreamErrorStanza) => {
+                Message::StreamError(decode("stream error", &bytes)?)
+            }
+            _ => Message::Other(tag, bytes.freeze()),
+        })
+    }
+
+    fn server_received(&mut self, last_stream_id_received: Option<i32>) {
+        if let Some(id) = last_stream_id_received {
+            self.acknowledged_stream_id = self.acknowledged_stream_id.max(id);
         }
     }
 
@@ -187,12 +519,6 @@ where
             let mut bytes = self.receive_buffer.iter();
             if let Some(tag_value) = bytes.next() {
                 let tag_value = *tag_value;
-                let tag = MessageTag::try_from(tag_value);
-                if matches!(tag, Ok(MessageTag::Close)) {
-                    self.bytes_required = 0;
-                    self.receive_buffer.clear();
-                    return Poll::Ready(None);
-                }
 
                 // determine size of the message
                 let (size, offset) = Self::try_read_varint(bytes);
@@ -205,16 +531,14 @@ where
 
                     self.receive_buffer.advance(offset);
                     let bytes = self.receive_buffer.split_to(size);
-                    return Poll::Ready(Some(Ok(match tag {
-                        Ok(MessageTag::DataMessageStanza) => {
-                            match DataMessage::decode(&self.eckey, &self.auth_secret, &bytes) {
-                                Err(e) => return Poll::Ready(Some(Err(e))),
-                                Ok(m) => Message::Data(m),
-                            }
-                        }
-                        Ok(MessageTag::HeartbeatPing) => Message::HeartbeatPing,
-                        _ => Message::Other(tag_value, bytes.into()),
-                    })));
+                    let message = self.decode(tag_value, bytes);
+                    if matches!(message, Ok(Message::Close)) {
+                        // end the stream after handing out the close
+                        self.bytes_required = 0;
+                        self.receive_buffer.clear();
+                    }
+
+                    return Poll::Ready(Some(message));
                 }
 
                 // ensure buffer can contain at least the current message
@@ -273,14 +597,198 @@ impl<T> std::ops::DerefMut for MessageStream<T> {
     }
 }
 
-pub fn new_heartbeat_ack() -> BytesMut {
+/// MCS IQ extension carrying a `SelectiveAck`
+const SELECTIVE_ACK_EXTENSION: i32 = 12;
+
+/// MCS IQ extension carrying a `StreamAck`
+const STREAM_ACK_EXTENSION: i32 = 13;
+
+/// Received frames after which the server expects a `StreamAck`
+const UNACKED_FRAME_LIMIT: i32 = 10;
+
+pub(crate) fn encode_frame(tag: MessageTag, message: &impl prost::Message) -> BytesMut {
     use bytes::BufMut;
 
-    let ack = crate::mcs::HeartbeatAck::default();
-    let mut bytes = BytesMut::with_capacity(prost::Message::encoded_len(&ack) + 5);
-    bytes.put_u8(MessageTag::HeartbeatAck as u8);
-    prost::Message::encode_length_delimited(&ack, &mut bytes)
-        .expect("heartbeat ack serialization should succeed");
+    let mut bytes = BytesMut::with_capacity(message.encoded_len() + 5);
+    bytes.put_u8(tag as u8);
+    message
+        .encode_length_delimited(&mut bytes)
+        .expect("MCS frame serialization should succeed");
 
     bytes
 }
+
+fn selective_ack_stanza(iq_id: String, persistent_ids: Vec<String>) -> crate::mcs::IqStanza {
+    let ack = crate::mcs::SelectiveAck { id: persistent_ids };
+    crate::mcs::IqStanza {
+        r#type: crate::mcs::iq_stanza::IqType::Set as i32,
+        id: iq_id,
+        extension: Some(crate::mcs::Extension {
+            id: SELECTIVE_ACK_EXTENSION,
+            data: prost::Message::encode_to_vec(&ack),
+        }),
+        ..Default::default()
+    }
+}
+
+/// A heartbeat ping outside of stream ID accounting, prefer [`MessageStream::heartbeat_ping`]
+pub fn new_heartbeat_ping() -> BytesMut {
+    encode_frame(
+        MessageTag::HeartbeatPing,
+        &crate::mcs::HeartbeatPing::default(),
+    )
+}
+
+/// A heartbeat ack outside of stream ID accounting, prefer [`MessageStream::heartbeat_ack`]
+pub fn new_heartbeat_ack() -> BytesMut {
+    encode_frame(
+        MessageTag::HeartbeatAck,
+        &crate::mcs::HeartbeatAck::default(),
+    )
+}
+
+/// Acknowledge the given persistent IDs over the live connection, the server answers with an IQ
+/// result bearing the same `iq_id`
+pub fn new_selective_ack(iq_id: &str, persistent_ids: Vec<String>) -> BytesMut {
+    encode_frame(
+        MessageTag::IqStanza,
+        &selective_ack_stanza(iq_id.into(), persistent_ids),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EncryptedPayload, WebPushKeys};
+
+    fn keys() -> (WebPushKeys, EcKeyComponents) {
+        let keys = WebPushKeys::new().unwrap();
+        let eckey = EcKeyComponents::new(keys.private_key.clone(), keys.public_key.clone());
+        (keys, eckey)
+    }
+
+    fn set_field(stanza: &mut crate::mcs::DataMessageStanza, key: &str, value: &str) {
+        stanza.app_data.retain(|field| field.key != key);
+        stanza.app_data.push(crate::mcs::AppData {
+            key: key.into(),
+            value: value.into(),
+        });
+    }
+
+    fn encrypted(keys: &WebPushKeys, encoding: ContentEncoding) -> EncryptedPayload {
+        EncryptedPayload::encrypt(&keys.public_key, &keys.auth_secret, b"hello", encoding).unwrap()
+    }
+
+    #[test]
+    fn metadata_times_in_seconds() {
+        let stanza = crate::mcs::DataMessageStanza {
+            ttl: Some(60),
+            sent: Some(1_700_000_000),
+            queued: Some(5),
+            ..Default::default()
+        };
+
+        let message = DataMessage::new(stanza, Vec::new());
+        assert!(!message.encrypted);
+        assert_eq!(message.ttl, Some(Duration::from_secs(60)));
+        assert_eq!(
+            message.sent,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+        assert_eq!(message.queued, Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn content_encoding_names() {
+        assert_eq!(
+            ContentEncoding::parse(" AES128GCM "),
+            Some(ContentEncoding::Aes128Gcm)
+        );
+        assert_eq!(
+            ContentEncoding::parse("aesgcm"),
+            Some(ContentEncoding::AesGcm)
+        );
+        assert_eq!(ContentEncoding::parse("aesgcm128"), None);
+    }
+
+    #[test]
+    fn aes128gcm_named_and_detected() {
+        let (keys, eckey) = keys();
+        let payload = encrypted(&keys, ContentEncoding::Aes128Gcm);
+
+        let stanza = payload.data_message_stanza("0:1");
+        let message = DataMessage::decode(&eckey, &keys.auth_secret, stanza).unwrap();
+        assert_eq!(message.body, b"hello");
+        assert!(message.encrypted);
+
+        // without content-encoding or aesgcm parameters, aes128gcm is assumed
+        let mut stanza = payload.data_message_stanza("0:1");
+        stanza.app_data.clear();
+        let message = DataMessage::decode(&eckey, &keys.auth_secret, stanza).unwrap();
+        assert_eq!(message.body, b"hello");
+    }
+
+    #[test]
+    fn aesgcm_detected_without_content_encoding() {
+        let (keys, eckey) = keys();
+        let payload = encrypted(&keys, ContentEncoding::AesGcm);
+
+        let mut stanza = payload.data_message_stanza("0:1");
+        stanza
+            .app_data
+            .retain(|field| field.key != "content-encoding");
+        let message = DataMessage::decode(&eckey, &keys.auth_secret, stanza).unwrap();
+        assert_eq!(message.body, b"hello");
+    }
+
+    #[test]
+    fn unsupported_content_encoding() {
+        let (keys, eckey) = keys();
+        let mut stanza = encrypted(&keys, ContentEncoding::Aes128Gcm).data_message_stanza("0:1");
+        set_field(&mut stanza, "content-encoding", "aesgcm128");
+
+        let result = DataMessage::decode(&eckey, &keys.auth_secret, stanza);
+        assert!(
+            matches!(result, Err(Error::UnsupportedContentEncoding(ref encoding)) if encoding == "aesgcm128")
+        );
+    }
+
+    #[test]
+    fn aesgcm_crypto_key_with_vapid_key() {
+        let (keys, eckey) = keys();
+        let payload = encrypted(&keys, ContentEncoding::AesGcm);
+
+        let mut stanza = payload.data_message_stanza("0:1");
+        let crypto_key = format!("{};p256ecdsa=BExampleVapidKey", payload.crypto_key.unwrap());
+        set_field(&mut stanza, "crypto-key", &crypto_key);
+        let encryption = format!("rs=4096, {}", payload.encryption.unwrap());
+        set_field(&mut stanza, "encryption", &encryption);
+
+        let message = DataMessage::decode(&eckey, &keys.auth_secret, stanza).unwrap();
+        assert_eq!(message.body, b"hello");
+    }
+
+    #[test]
+    fn aesgcm_missing_parameters() {
+        let (keys, eckey) = keys();
+        let payload = encrypted(&keys, ContentEncoding::AesGcm);
+
+        for (key, value, missing) in [
+            ("crypto-key", "", "crypto-key"),
+            ("crypto-key", "dh", "crypto-key"),
+            ("crypto-key", "p256ecdsa=abc", "crypto-key"),
+            ("crypto-key", "ä", "crypto-key"),
+            ("encryption", "salt", "encryption"),
+            ("encryption", "sältz", "encryption"),
+        ] {
+            let mut stanza = payload.data_message_stanza("0:1");
+            set_field(&mut stanza, key, value);
+
+            let result = DataMessage::decode(&eckey, &keys.auth_secret, stanza);
+            assert!(
+                matches!(result, Err(Error::MissingCryptoMetadata(kind)) if kind == missing),
+                "{key}: {value:?}"
+            );
+        }
+    }
+}
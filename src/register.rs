@@ -1,4 +1,4 @@
-use crate::{fcm, firebase, gcm, Error};
+use crate::{fcm, firebase, gcm, Endpoints, Error, HttpTransport, RetryPolicy, Subscription};
 use serde::Deserialize;
 use serde::Serialize;
 use uuid::Uuid;
@@ -8,49 +8,254 @@ pub struct Registration {
     pub fcm_token: String,
     pub gcm: gcm::Session,
     pub keys: fcm::WebPushKeys,
+
+    /// GCM token FCM delivers to, empty for registrations saved before it was kept
+    #[serde(default)]
+    pub gcm_token: String,
+
+    /// App ID (`X-subtype`) the GCM token was issued for, empty for registrations saved before it
+    /// was kept
+    #[serde(default)]
+    pub gcm_app_id: String,
+
+    /// Firebase installation the FCM registration was made under, `None` for registrations saved
+    /// before it was kept
+    #[serde(default)]
+    pub installation: Option<firebase::Installation>,
+}
+
+impl Registration {
+    /// the web push subscription FCM was handed for this registration, for sending it pushes
+    pub fn subscription(&self, endpoints: &Endpoints) -> Subscription {
+        let endpoint = format!("{}/{}", endpoints.fcm_send, self.gcm_token);
+        Subscription::new(endpoint, &self.keys)
+    }
+
+    /// Tears the registration down: deletes the FCM registration, then the Firebase
+    /// installation, then unregisters the GCM token.
+    ///
+    /// Every step is attempted even if an earlier one failed, the report tells how each went.
+    pub async fn unregister(
+        &self,
+        http: &dyn HttpTransport,
+        endpoints: &Endpoints,
+    ) -> UnregisterReport {
+        let mut report = UnregisterReport::default();
+
+        if let Some(ref installation) = self.installation {
+            log::debug!("Deleting FCM registration");
+            let mut installation = installation.clone();
+            let result = match installation.auth_token(http, endpoints).await {
+                Ok(auth_token) => {
+                    let auth_token = auth_token.value.clone();
+                    fcm::delete_registration(
+                        http,
+                        endpoints,
+                        &installation.project_id,
+                        &installation.api_key,
+                        &auth_token,
+                        &self.fcm_token,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            report.fcm_registration = Some(result);
+
+            log::debug!("Deleting Firebase installation");
+            report.installation = Some(installation.delete(http, endpoints).await);
+        }
+
+        if !self.gcm_app_id.is_empty() {
+            log::debug!("Unregistering from GCM");
+            report.gcm = Some(
+                self.gcm
+                    .delete_token(http, endpoints, &self.gcm_app_id)
+                    .await,
+            );
+        }
+
+        report
+    }
+}
+
+/// Outcome of each step of [`Registration::unregister`].
+///
+/// A step is `None` when the registration lacks what it needs, which happens for registrations
+/// saved before the Firebase installation and GCM app ID were kept.
+#[derive(Debug, Default)]
+pub struct UnregisterReport {
+    pub fcm_registration: Option<Result<(), Error>>,
+    pub installation: Option<Result<(), Error>>,
+    pub gcm: Option<Result<(), Error>>,
+}
+
+impl UnregisterReport {
+    /// whether every step ran and succeeded
+    pub fn is_complete(&self) -> bool {
+        [&self.fcm_registration, &self.installation, &self.gcm]
+            .into_iter()
+            .all(|step| matches!(step, Some(Ok(()))))
+    }
+
+    /// errors of the failed steps, with the step's name
+    pub fn errors(&self) -> impl Iterator<Item = (&'static str, &Error)> {
+        [
+            ("FCM registration", &self.fcm_registration),
+            ("Firebase installation", &self.installation),
+            ("GCM registration", &self.gcm),
+        ]
+        .into_iter()
+        .filter_map(|(step, result)| match result {
+            Some(Err(e)) => Some((step, e)),
+            _ => None,
+        })
+    }
 }
 
+/// Registers a new device and a Firebase app on it, retrying failed steps with the default
+/// [`RetryPolicy`].
 pub async fn register(
-    http: &reqwest::Client,
+    http: &dyn HttpTransport,
+    endpoints: &Endpoints,
     firebase_app_id: &str,
     firebase_project_id: &str,
     firebase_api_key: &str,
     vapid_key: Option<&str>,
 ) -> Result<Registration, Error> {
-    log::debug!("Checking in to GCM");
-    let gcm_session = gcm::Session::create(http).await?;
-
-    let id = Uuid::new_v4();
-    let gcm_app_id = format!("wp:receiver.push.com#{id}");
+    register_with_retry(
+        http,
+        endpoints,
+        &RetryPolicy::default(),
+        firebase_app_id,
+        firebase_project_id,
+        firebase_api_key,
+        vapid_key,
+    )
+    .await
+}
 
-    log::debug!("Registering to GCM");
-    let gcm_token = gcm_session.request_token(&gcm_app_id).await?;
+/// [`register`] with the given retry policy, which applies to each step on its own: a retry never
+/// redoes a step that already succeeded.
+///
+/// The device checks in with the default [`ClientIdentity`](crate::ClientIdentity); to use
+/// another, create the session with [`Session::create`](crate::Session::create) and pass it to
+/// [`register_app_with_retry`].
+pub async fn register_with_retry(
+    http: &dyn HttpTransport,
+    endpoints: &Endpoints,
+    retry: &RetryPolicy,
+    firebase_app_id: &str,
+    firebase_project_id: &str,
+    firebase_api_key: &str,
+    vapid_key: Option<&str>,
+) -> Result<Registration, Error> {
+    log::debug!("Checking in to GCM");
+    let identity = gcm::ClientIdentity::default();
+    let gcm_session = retry
+        .retry("GCM checkin", || {
+            gcm::Session::create(http, endpoints, &identity)
+        })
+        .await?;
 
-    log::debug!("Getting Firebase installation token");
-    let firebase_installation_token = firebase::InstallationAuthToken::request(
+    register_app_with_retry(
         http,
+        endpoints,
+        retry,
+        &gcm_session,
         firebase_app_id,
         firebase_project_id,
         firebase_api_key,
+        vapid_key,
     )
-    .await?;
+    .await
+}
 
-    log::debug!("Calling FCM register");
-    let fcm_register_result = fcm::Registration::request(
+/// Registers a Firebase app on an existing GCM session, under an app ID and web push keys of its
+/// own, retrying failed steps with the default [`RetryPolicy`].
+///
+/// Apps registered on the same session share the android ID, so one connection receives the
+/// messages of all of them, see [`PushListener::with_app`](crate::PushListener::with_app).
+pub async fn register_app(
+    http: &dyn HttpTransport,
+    endpoints: &Endpoints,
+    gcm_session: &gcm::Session,
+    firebase_app_id: &str,
+    firebase_project_id: &str,
+    firebase_api_key: &str,
+    vapid_key: Option<&str>,
+) -> Result<Registration, Error> {
+    register_app_with_retry(
         http,
+        endpoints,
+        &RetryPolicy::default(),
+        gcm_session,
+        firebase_app_id,
         firebase_project_id,
         firebase_api_key,
         vapid_key,
-        &firebase_installation_token.value,
-        &gcm_token,
     )
-    .await?;
+    .await
+}
+
+/// [`register_app`] with the given retry policy, applied to each step on its own
+#[allow(clippy::too_many_arguments)]
+pub async fn register_app_with_retry(
+    http: &dyn HttpTransport,
+    endpoints: &Endpoints,
+    retry: &RetryPolicy,
+    gcm_session: &gcm::Session,
+    firebase_app_id: &str,
+    firebase_project_id: &str,
+    firebase_api_key: &str,
+    vapid_key: Option<&str>,
+) -> Result<Registration, Error> {
+    let id = Uuid::new_v4();
+    let gcm_app_id = format!("wp:receiver.push.com#{id}");
+
+    log::debug!("Registering to GCM");
+    let gcm_token = retry
+        .retry("GCM registration", || {
+            gcm_session.request_token(http, endpoints, &gcm_app_id)
+        })
+        .await?;
+
+    log::debug!("Creating Firebase installation");
+    let installation = retry
+        .retry("Firebase installation", || {
+            firebase::Installation::create(
+                http,
+                endpoints,
+                firebase_app_id,
+                firebase_project_id,
+                firebase_api_key,
+            )
+        })
+        .await?;
+
+    log::debug!("Calling FCM register");
+    let fcm_register_result = retry
+        .retry("FCM registration", || {
+            fcm::Registration::request(
+                http,
+                endpoints,
+                firebase_project_id,
+                firebase_api_key,
+                vapid_key,
+                &installation.auth_token.value,
+                &gcm_token,
+            )
+        })
+        .await?;
 
     log::debug!("Registration complete");
 
     Ok(Registration {
-        gcm: gcm_session,
+        gcm: gcm_session.clone(),
         fcm_token: fcm_register_result.fcm_token,
         keys: fcm_register_result.keys,
+        gcm_token,
+        gcm_app_id,
+        installation: Some(installation),
     })
 }
@@ -0,0 +1,106 @@
+use crate::Error;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often and how patiently a failing step is retried.
+///
+/// Only errors for which [`Error::is_retryable`] holds are retried. The delay grows by
+/// `multiplier` after every failed attempt, and a random part of up to half of it is taken off so
+/// clients failing together don't retry together.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts per step, including the first one
+    pub max_attempts: u32,
+
+    /// Delay before the first retry
+    pub initial_delay: Duration,
+
+    /// Upper bound for the delay between attempts
+    pub max_delay: Duration,
+
+    /// Factor applied to the delay after every failed attempt
+    pub multiplier: u32,
+
+    /// No retry is started that would end later than this after the first attempt
+    pub max_elapsed: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2,
+            max_elapsed: Some(Duration::from_secs(2 * 60)),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// every step is attempted once
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// delay before the retry following the given number of failures, with jitter
+    fn delay(&self, failures: u32) -> Duration {
+        let factor = self.multiplier.saturating_pow(failures.saturating_sub(1));
+        let delay = self
+            .initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay);
+
+        let millis = u64::try_from(delay.as_millis()).unwrap_or(u64::MAX);
+        Duration::from_millis(rand::random_range(millis / 2..=millis))
+    }
+
+    /// Runs `attempt` until it succeeds, fails for good or the policy runs out.
+    ///
+    /// A `Retry-After` the service sent is waited out when it is longer than the policy's delay.
+    pub(crate) async fn retry<T, F, Fut>(&self, step: &str, mut attempt: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let started = Instant::now();
+        let mut failures = 0;
+
+        loop {
+            let error = match attempt().await {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+
+            failures += 1;
+            if !error.is_retryable() || failures >= self.max_attempts {
+                return Err(error);
+            }
+
+            let delay = match error {
+                Error::GoogleApi {
+                    retry_after: Some(retry_after),
+                    ..
+                }
+                | Error::PushRejected {
+                    retry_after: Some(retry_after),
+                    ..
+                } => retry_after.max(self.delay(failures)),
+                _ => self.delay(failures),
+            };
+            if self
+                .max_elapsed
+                .is_some_and(|max_elapsed| started.elapsed() + delay > max_elapsed)
+            {
+                return Err(error);
+            }
+
+            log::warn!("{step} failed, retrying in {delay:?}: {error}");
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
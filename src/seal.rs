@@ -0,0 +1,464 @@
+use crate::fcm::{from_base64, to_base64};
+use crate::{gcm, Error, Installation, Registration, WebPushKeys};
+use openssl::symm::Cipher;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::sync::Mutex;
+
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+const SALT_LENGTH: usize = 16;
+
+/// scrypt cost for passphrase derived keys, 2^15 iterations with 32 MiB of memory
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+/// Bound into every seal so the ciphertext can't be reused for another format
+const FORMAT: &str = "fcm-push-listener sealed registration v1";
+
+/// Key that seals the secrets of a registration, see [`Registration::seal`].
+pub struct SealingKey {
+    secret: Secret,
+}
+
+enum Secret {
+    Key([u8; KEY_LENGTH]),
+    Passphrase {
+        passphrase: String,
+
+        /// the salt and key of the last derivation, so saving again doesn't run scrypt again
+        derived: Mutex<Option<(ScryptParams, [u8; KEY_LENGTH])>>,
+    },
+}
+
+impl SealingKey {
+    /// a 256 bit key for AES-GCM, which should come from a key store rather than a file next to
+    /// the sealed data
+    pub fn from_key(key: [u8; KEY_LENGTH]) -> Self {
+        Self {
+            secret: Secret::Key(key),
+        }
+    }
+
+    /// a key derived from `passphrase` with scrypt, under a random salt stored with the data
+    pub fn from_passphrase(passphrase: impl Into<String>) -> Self {
+        Self {
+            secret: Secret::Passphrase {
+                passphrase: passphrase.into(),
+                derived: Mutex::new(None),
+            },
+        }
+    }
+
+    /// key and derivation parameters to seal new data with
+    fn sealing(&self) -> Result<(Option<ScryptParams>, [u8; KEY_LENGTH]), Error> {
+        let (passphrase, derived) = match self.secret {
+            Secret::Key(key) => return Ok((None, key)),
+            Secret::Passphrase {
+                ref passphrase,
+                ref derived,
+            } => (passphrase, derived),
+        };
+
+        let mut derived = derived.lock().unwrap();
+        if let Some((ref params, key)) = *derived {
+            return Ok((Some(params.clone()), key));
+        }
+
+        let mut salt = vec![0; SALT_LENGTH];
+        openssl::rand::rand_bytes(&mut salt).map_err(|e| Error::Seal("salt generation", e))?;
+        let params = ScryptParams {
+            salt,
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+        };
+
+        let key = params.derive(passphrase)?;
+        *derived = Some((params.clone(), key));
+        Ok((Some(params), key))
+    }
+
+    /// key to open data sealed with the given derivation parameters
+    fn opening(&self, params: Option<&ScryptParams>) -> Result<[u8; KEY_LENGTH], Error> {
+        match (&self.secret, params) {
+            (Secret::Key(key), None) => Ok(*key),
+            (
+                Secret::Passphrase {
+                    passphrase,
+                    derived,
+                },
+                Some(params),
+            ) => {
+                let mut derived = derived.lock().unwrap();
+                if let Some((ref cached, key)) = *derived {
+                    if cached == params {
+                        return Ok(key);
+                    }
+                }
+
+                let key = params.derive(passphrase)?;
+                *derived = Some((params.clone(), key));
+                Ok(key)
+            }
+            // a passphrase can't open data sealed under a raw key and the other way round
+            _ => Err(Error::WrongSealingKey),
+        }
+    }
+}
+
+impl std::fmt::Debug for SealingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.secret {
+            Secret::Key(_) => write!(f, "SealingKey::Key(..)"),
+            Secret::Passphrase { .. } => write!(f, "SealingKey::Passphrase(..)"),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ScryptParams {
+    #[serde(deserialize_with = "from_base64", serialize_with = "to_base64")]
+    salt: Vec<u8>,
+    log_n: u8,
+    r: u32,
+    p: u32,
+}
+
+impl ScryptParams {
+    fn derive(&self, passphrase: &str) -> Result<[u8; KEY_LENGTH], Error> {
+        const MAX_MEMORY: u64 = 256 * 1024 * 1024;
+
+        // the parameters come from the stored file, keep a tampered one from pinning the CPU or
+        // exhausting memory
+        let acceptable = (1..=24).contains(&self.log_n)
+            && (1..=32).contains(&self.r)
+            && (1..=16).contains(&self.p)
+            && (128 * u64::from(self.r)) << self.log_n <= MAX_MEMORY;
+        if !acceptable {
+            return Err(Error::UnsupportedKeyDerivation);
+        }
+
+        let mut key = [0; KEY_LENGTH];
+        openssl::pkcs5::scrypt(
+            passphrase.as_bytes(),
+            &self.salt,
+            1 << self.log_n,
+            self.r.into(),
+            self.p.into(),
+            MAX_MEMORY,
+            &mut key,
+        )
+        .map_err(|e| Error::Seal("key derivation", e))?;
+
+        Ok(key)
+    }
+}
+
+/// A [`Registration`] whose private key, auth secret, GCM security token and Firebase installation
+/// are encrypted with AES-256-GCM.
+///
+/// The tokens, android ID and public key stay readable, and are authenticated along with the
+/// secrets.
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SealedRegistration {
+    pub fcm_token: String,
+
+    #[serde(default)]
+    pub gcm_token: String,
+
+    #[serde(default)]
+    pub gcm_app_id: String,
+
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    pub android_id: i64,
+
+    #[serde(deserialize_with = "from_base64", serialize_with = "to_base64")]
+    pub public_key: Vec<u8>,
+
+    /// how the key was derived from a passphrase, absent for raw keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scrypt: Option<ScryptParams>,
+
+    #[serde(deserialize_with = "from_base64", serialize_with = "to_base64")]
+    nonce: Vec<u8>,
+
+    /// ciphertext followed by the GCM tag
+    #[serde(deserialize_with = "from_base64", serialize_with = "to_base64")]
+    secrets: Vec<u8>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+struct Secrets {
+    #[serde(deserialize_with = "from_base64", serialize_with = "to_base64")]
+    private_key: Vec<u8>,
+
+    #[serde(deserialize_with = "from_base64", serialize_with = "to_base64")]
+    auth_secret: Vec<u8>,
+
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    security_token: u64,
+
+    /// holds the installation's refresh token
+    #[serde(default)]
+    installation: Option<Installation>,
+}
+
+impl Registration {
+    /// Encrypts the secrets of this registration, for saving it where others may read it.
+    pub fn seal(&self, key: &SealingKey) -> Result<SealedRegistration, Error> {
+        let (scrypt, key) = key.sealing()?;
+
+        let mut nonce = vec![0; NONCE_LENGTH];
+        openssl::rand::rand_bytes(&mut nonce).map_err(|e| Error::Seal("nonce generation", e))?;
+
+        let mut sealed = SealedRegistration {
+            fcm_token: self.fcm_token.clone(),
+            gcm_token: self.gcm_token.clone(),
+            gcm_app_id: self.gcm_app_id.clone(),
+            android_id: self.gcm.android_id,
+            public_key: self.keys.public_key.clone(),
+            scrypt,
+            nonce,
+            secrets: Vec::new(),
+        };
+
+        let secrets = Secrets {
+            private_key: self.keys.private_key.clone(),
+            auth_secret: self.keys.auth_secret.clone(),
+            security_token: self.gcm.security_token,
+            installation: self.installation.clone(),
+        };
+        let plaintext =
+            serde_json::to_vec(&secrets).map_err(|e| Error::Json("registration secrets", e))?;
+
+        let mut tag = [0; TAG_LENGTH];
+        let mut ciphertext = openssl::symm::encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(&sealed.nonce),
+            &sealed.associated_data(),
+            &plaintext,
+            &mut tag,
+        )
+        .map_err(|e| Error::Seal("encryption", e))?;
+        ciphertext.extend_from_slice(&tag);
+        sealed.secrets = ciphertext;
+
+        Ok(sealed)
+    }
+}
+
+impl SealedRegistration {
+    /// Decrypts the registration, failing with [`Error::WrongSealingKey`] when `key` isn't the
+    /// one it was sealed with or the data was altered.
+    pub fn open(&self, key: &SealingKey) -> Result<Registration, Error> {
+        let key = key.opening(self.scrypt.as_ref())?;
+
+        let split = self
+            .secrets
+            .len()
+            .checked_sub(TAG_LENGTH)
+            .ok_or(Error::WrongSealingKey)?;
+        let (ciphertext, tag) = self.secrets.split_at(split);
+
+        let plaintext = openssl::symm::decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(&self.nonce),
+            &self.associated_data(),
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| Error::WrongSealingKey)?;
+        let secrets: Secrets = serde_json::from_slice(&plaintext)
+            .map_err(|e| Error::Json("registration secrets", e))?;
+
+        Ok(Registration {
+            fcm_token: self.fcm_token.clone(),
+            gcm: gcm::Session {
+                android_id: self.android_id,
+                security_token: secrets.security_token,
+            },
+            keys: WebPushKeys {
+                public_key: self.public_key.clone(),
+                private_key: secrets.private_key,
+                auth_secret: secrets.auth_secret,
+            },
+            gcm_token: self.gcm_token.clone(),
+            gcm_app_id: self.gcm_app_id.clone(),
+            installation: secrets.installation,
+        })
+    }
+
+    /// the readable fields, so they can't be swapped between sealed registrations
+    fn associated_data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for field in [
+            FORMAT,
+            &self.fcm_token,
+            &self.gcm_token,
+            &self.gcm_app_id,
+            &self.android_id.to_string(),
+        ] {
+            data.extend_from_slice(field.as_bytes());
+            data.push(0);
+        }
+        data.extend_from_slice(&self.public_key);
+
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registration() -> Registration {
+        Registration {
+            fcm_token: "fcm-token".into(),
+            gcm: gcm::Session {
+                android_id: 4_242_424_242,
+                security_token: 1_234_567_890,
+            },
+            keys: WebPushKeys::new().unwrap(),
+            gcm_token: "gcm-token".into(),
+            gcm_app_id: "wp:app#1".into(),
+            installation: None,
+        }
+    }
+
+    fn assert_opens(sealed: &SealedRegistration, key: &SealingKey, original: &Registration) {
+        let opened = sealed.open(key).unwrap();
+        assert_eq!(opened.fcm_token, original.fcm_token);
+        assert_eq!(opened.gcm.android_id, original.gcm.android_id);
+        assert_eq!(opened.gcm.security_token, original.gcm.security_token);
+        assert_eq!(opened.keys.public_key, original.keys.public_key);
+        assert_eq!(opened.keys.private_key, original.keys.private_key);
+        assert_eq!(opened.keys.auth_secret, original.keys.auth_secret);
+        assert_eq!(opened.gcm_token, original.gcm_token);
+        assert_eq!(opened.gcm_app_id, original.gcm_app_id);
+    }
+
+    fn assert_wrong_key(result: Result<Registration, Error>) {
+        assert!(matches!(result, Err(Error::WrongSealingKey)));
+    }
+
+    #[test]
+    fn seal_then_open_with_key() {
+        let registration = registration();
+        let key = SealingKey::from_key([7; KEY_LENGTH]);
+
+        let sealed = registration.seal(&key).unwrap();
+        assert!(!sealed.secrets.is_empty());
+        assert_opens(&sealed, &key, &registration);
+    }
+
+    #[test]
+    fn seal_then_open_with_passphrase() {
+        let registration = registration();
+        let sealed = registration
+            .seal(&SealingKey::from_passphrase("correct horse"))
+            .unwrap();
+
+        // a fresh key has to derive again from the stored salt
+        assert_opens(
+            &sealed,
+            &SealingKey::from_passphrase("correct horse"),
+            &registration,
+        );
+    }
+
+    #[test]
+    fn wrong_key() {
+        let sealed = registration()
+            .seal(&SealingKey::from_key([7; KEY_LENGTH]))
+            .unwrap();
+        assert_wrong_key(sealed.open(&SealingKey::from_key([8; KEY_LENGTH])));
+
+        let sealed = registration()
+            .seal(&SealingKey::from_passphrase("correct horse"))
+            .unwrap();
+        assert_wrong_key(sealed.open(&SealingKey::from_passphrase("battery staple")));
+    }
+
+    #[test]
+    fn passphrase_and_key_are_not_interchangeable() {
+        let key = [7; KEY_LENGTH];
+
+        let sealed = registration().seal(&SealingKey::from_key(key)).unwrap();
+        assert_wrong_key(sealed.open(&SealingKey::from_passphrase("correct horse")));
+
+        let sealed = registration()
+            .seal(&SealingKey::from_passphrase("correct horse"))
+            .unwrap();
+        assert_wrong_key(sealed.open(&SealingKey::from_key(key)));
+    }
+
+    #[test]
+    fn tampered_ciphertext() {
+        let key = SealingKey::from_key([7; KEY_LENGTH]);
+        let sealed = registration().seal(&key).unwrap();
+
+        let mut tampered = sealed.clone();
+        tampered.secrets[0] ^= 1;
+        assert_wrong_key(tampered.open(&key));
+
+        let mut tampered = sealed.clone();
+        tampered.nonce[0] ^= 1;
+        assert_wrong_key(tampered.open(&key));
+
+        let mut truncated = sealed;
+        truncated.secrets.truncate(TAG_LENGTH - 1);
+        assert_wrong_key(truncated.open(&key));
+    }
+
+    #[test]
+    fn tampered_associated_data() {
+        let key = SealingKey::from_key([7; KEY_LENGTH]);
+        let sealed = registration().seal(&key).unwrap();
+        let other = registration();
+
+        let tamperings: [fn(&mut SealedRegistration, &Registration); 6] = [
+            |sealed, _| sealed.fcm_token.push('x'),
+            |sealed, _| sealed.gcm_token.push('x'),
+            |sealed, _| sealed.gcm_app_id.push('x'),
+            |sealed, _| sealed.gcm_app_id.clear(),
+            |sealed, _| sealed.android_id += 1,
+            |sealed, other| sealed.public_key = other.keys.public_key.clone(),
+        ];
+        for tamper in tamperings {
+            let mut tampered = sealed.clone();
+            tamper(&mut tampered, &other);
+            assert_wrong_key(tampered.open(&key));
+        }
+    }
+
+    #[test]
+    fn unreasonable_scrypt_parameters() {
+        let key = SealingKey::from_passphrase("correct horse");
+        let sealed = registration().seal(&key).unwrap();
+
+        let tamperings: [fn(&mut ScryptParams); 5] = [
+            |params| params.log_n = 0,
+            |params| params.log_n = 64,
+            |params| params.r = 1 << 20,
+            |params| params.p = u32::MAX,
+            // within each bound, but over the memory limit together
+            |params| {
+                params.log_n = 24;
+                params.r = 32;
+            },
+        ];
+        for tamper in tamperings {
+            let mut tampered = sealed.clone();
+            tamper(tampered.scrypt.as_mut().unwrap());
+            let result = tampered.open(&SealingKey::from_passphrase("correct horse"));
+            assert!(matches!(result, Err(Error::UnsupportedKeyDerivation)));
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use crate::error::{parse_retry_after, status_reason};
+use crate::{ContentEncoding, EncryptedPayload, Error, HttpRequest, HttpTransport, WebPushKeys};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as Base64;
+use base64::Engine;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey, EcPoint, PointConversionForm};
+use openssl::ecdsa::EcdsaSig;
+use openssl::nid::Nid;
+use openssl::pkey::Private;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// How long a VAPID token stays valid, RFC 8292 allows at most 24 hours
+const VAPID_TOKEN_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Where a push goes and the keys it is encrypted for
+#[derive(Clone, Debug)]
+pub struct Subscription {
+    pub endpoint: String,
+
+    /// Receiver's public key, uncompressed P-256 point
+    pub public_key: Vec<u8>,
+    pub auth_secret: Vec<u8>,
+}
+
+impl Subscription {
+    pub fn new(endpoint: impl Into<String>, keys: &WebPushKeys) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            public_key: keys.public_key.clone(),
+            auth_secret: keys.auth_secret.clone(),
+        }
+    }
+}
+
+/// P-256 key pair identifying an application server to push services (RFC 8292)
+#[derive(Clone)]
+pub struct VapidKeys {
+    key: EcKey<Private>,
+}
+
+impl VapidKeys {
+    pub fn generate() -> Result<Self, Error> {
+        let group = p256().map_err(|e| Error::Vapid("key generation", e))?;
+        let key = EcKey::generate(&group).map_err(|e| Error::Vapid("key generation", e))?;
+        Ok(Self { key })
+    }
+
+    /// restores a key pair from the raw 32 byte private key, as returned by
+    /// [`private_key`](Self::private_key)
+    pub fn from_private_key(private_key: &[u8]) -> Result<Self, Error> {
+        const OPERATION: &str = "private key import";
+
+        let key = (|| {
+            let group = p256()?;
+            let private_number = BigNum::from_slice(private_key)?;
+            let mut context = BigNumContext::new()?;
+            let mut public_point = EcPoint::new(&group)?;
+            public_point.mul_generator2(&group, &private_number, &mut context)?;
+
+            let key = EcKey::from_private_components(&group, &private_number, &public_point)?;
+            key.check_key()?;
+            Ok(key)
+        })()
+        .map_err(|e| Error::Vapid(OPERATION, e))?;
+
+        Ok(Self { key })
+    }
+
+    pub fn private_key(&self) -> Vec<u8> {
+        self.key
+            .private_key()
+            .to_vec_padded(32)
+            .expect("P-256 private key should fit in 32 bytes")
+    }
+
+    /// Uncompressed P-256 point
+    pub fn public_key(&self) -> Vec<u8> {
+        let mut context = BigNumContext::new().expect("BigNum context allocation failure");
+        self.key
+            .public_key()
+            .to_bytes(
+                self.key.group(),
+                PointConversionForm::UNCOMPRESSED,
+                &mut context,
+            )
+            .expect("P-256 public key serialization failure")
+    }
+
+    /// Public key in URL safe base64, the form taken by `register`'s `vapid_key` and by
+    /// `applicationServerKey` in browsers
+    pub fn application_server_key(&self) -> String {
+        Base64.encode(self.public_key())
+    }
+
+    /// Signs a VAPID JWT for the push service at `audience` (scheme, host and port of the
+    /// endpoint), `subject` being a `mailto:` or `https:` contact for the sender.
+    pub fn sign(
+        &self,
+        audience: &str,
+        subject: &str,
+        expires: SystemTime,
+    ) -> Result<String, Error> {
+        const OPERATION: &str = "token signing";
+
+        let expires = expires
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let header = Base64.encode(r#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = serde_json::json!({
+            "aud": audience,
+            "exp": expires,
+            "sub": subject,
+        });
+        let signing_input = format!("{header}.{}", Base64.encode(claims.to_string()));
+
+        // JWS wants the raw r || s pair, not the DER structure openssl produces
+        let digest = openssl::sha::sha256(signing_input.as_bytes());
+        let signature = EcdsaSig::sign(&digest, &self.key)
+            .and_then(|signature| {
+                let mut raw = signature.r().to_vec_padded(32)?;
+                raw.extend(signature.s().to_vec_padded(32)?);
+                Ok(raw)
+            })
+            .map_err(|e| Error::Vapid(OPERATION, e))?;
+
+        Ok(format!("{signing_input}.{}", Base64.encode(signature)))
+    }
+}
+
+fn p256() -> Result<EcGroup, openssl::error::ErrorStack> {
+    EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)
+}
+
+/// How soon the receiver needs the message, lets the push service save battery on the device
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Urgency {
+    VeryLow,
+    Low,
+    Normal,
+    High,
+}
+
+impl Urgency {
+    /// the `Urgency` header value
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::VeryLow => "very-low",
+            Self::Low => "low",
+            Self::Normal => "normal",
+            Self::High => "high",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PushOptions {
+    /// How long the push service keeps the message while the receiver is offline
+    pub ttl: Duration,
+    pub urgency: Option<Urgency>,
+
+    /// A pending message with the same topic is replaced by this one, at most 32 characters of
+    /// URL safe base64
+    pub topic: Option<String>,
+    pub encoding: ContentEncoding,
+}
+
+impl Default for PushOptions {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(28 * 24 * 60 * 60),
+            urgency: None,
+            topic: None,
+            encoding: ContentEncoding::Aes128Gcm,
+        }
+    }
+}
+
+/// Sends web push messages (RFC 8030) to subscriptions such as the ones this crate registers.
+pub struct PushSender {
+    http: Arc<dyn HttpTransport>,
+    vapid: Option<(VapidKeys, String)>,
+}
+
+impl PushSender {
+    /// a sender posting through `http`, such as a `reqwest::Client`
+    pub fn new(http: impl HttpTransport + 'static) -> Self {
+        Self {
+            http: Arc::new(http),
+            vapid: None,
+        }
+    }
+
+    /// sign every push with the given keys; `subject` is a `mailto:` or `https:` contact for the
+    /// push service operator
+    pub fn with_vapid(mut self, keys: VapidKeys, subject: impl Into<String>) -> Self {
+        self.vapid = Some((keys, subject.into()));
+        self
+    }
+
+    /// Encrypts `payload` for the subscription and posts it to its endpoint.
+    ///
+    /// Returns the message location the push service answered with, if any.
+    pub async fn send(
+        &self,
+        subscription: &Subscription,
+        payload: &[u8],
+        options: &PushOptions,
+    ) -> Result<Option<String>, Error> {
+        const API_NAME: &str = "Web push";
+
+        let payload = EncryptedPayload::encrypt(
+            &subscription.public_key,
+            &subscription.auth_secret,
+            payload,
+            options.encoding,
+        )?;
+
+        let mut request = HttpRequest::post(&subscription.endpoint)
+            .header("TTL", options.ttl.as_secs().to_string())
+            .header("Content-Type", "application/octet-stream");
+        if let Some(urgency) = options.urgency {
+            request = request.header("Urgency", urgency.as_str());
+        }
+        if let Some(ref topic) = options.topic {
+            request = request.header("Topic", topic);
+        }
+
+        let mut headers = payload.headers();
+        if let Some((ref keys, ref subject)) = self.vapid {
+            let audience = url::Url::parse(&subscription.endpoint)
+                .map(|url| url.origin().ascii_serialization())
+                .map_err(|_| Error::DependencyFailure(API_NAME, "endpoint is not a valid URL"))?;
+            let token = keys.sign(&audience, subject, SystemTime::now() + VAPID_TOKEN_LIFETIME)?;
+            let public_key = keys.application_server_key();
+
+            match options.encoding {
+                ContentEncoding::Aes128Gcm => {
+                    headers.push(("Authorization", format!("vapid t={token}, k={public_key}")))
+                }
+                // the legacy scheme carries the key next to the sender's DH key
+                ContentEncoding::AesGcm => {
+                    headers.push(("Authorization", format!("WebPush {token}")));
+                    for (name, value) in headers.iter_mut() {
+                        if *name == "Crypto-Key" {
+                            value.push_str(&format!(";p256ecdsa={public_key}"));
+                        }
+                    }
+                }
+            }
+        }
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+
+        let response = request
+            .body(payload.body)
+            .send(self.http.as_ref(), API_NAME)
+            .await?;
+
+        if response.is_success() {
+            return Ok(response.header("Location").map(String::from));
+        }
+
+        Err(Error::PushRejected {
+            status: response.status,
+            reason: rejection_reason(&response.text())
+                .unwrap_or_else(|| status_reason(response.status).into()),
+            retry_after: response.header("Retry-After").and_then(parse_retry_after),
+        })
+    }
+}
+
+/// picks the reason out of a push service error body, which is JSON or plain text
+fn rejection_reason(body: &str) -> Option<String> {
+    let body = body.trim();
+    if body.is_empty() {
+        return None;
+    }
+
+    let Ok(json) = serde_json::from_str::<serde_json::Value>(body) else {
+        return Some(body.to_string());
+    };
+
+    let reason = [
+        &json["error"]["message"],
+        &json["error"],
+        &json["message"],
+        &json["reason"],
+    ]
+    .into_iter()
+    .find_map(|value| value.as_str());
+
+    Some(reason.map_or_else(|| body.to_string(), String::from))
+}
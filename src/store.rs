@@ -0,0 +1,174 @@
+use crate::{Error, Registration, SealedRegistration, SealingKey};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A registration together with the persistent IDs of the messages received for it that the push
+/// service hasn't confirmed yet
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoredRegistration {
+    pub registration: Registration,
+
+    #[serde(default)]
+    pub received_persistent_ids: Vec<String>,
+
+    /// further apps registered on the same GCM session, see
+    /// [`PushListener::with_app`](crate::PushListener::with_app)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub apps: Vec<Registration>,
+}
+
+/// [`StoredRegistration`] with the registrations' secrets sealed
+#[derive(Serialize, Deserialize)]
+struct SealedStoredRegistration {
+    registration: SealedRegistration,
+
+    #[serde(default)]
+    received_persistent_ids: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    apps: Vec<SealedRegistration>,
+}
+
+/// Persistence for a registration and its received persistent IDs.
+///
+/// [`PushListener::with_store`](crate::PushListener::with_store) saves to it whenever either
+/// changes. Inside a Tokio runtime the listener saves on the blocking thread pool, one save at a
+/// time, so `save` may block on file or database I/O. A save that falls behind is skipped in favor
+/// of the newest state, and [`PushListener::flush`](crate::PushListener::flush) writes one that
+/// hasn't happened yet.
+pub trait RegistrationStore: Send {
+    /// the saved state, `None` when nothing was saved yet
+    fn load(&self) -> Result<Option<StoredRegistration>, Error>;
+
+    fn save(&self, state: &StoredRegistration) -> Result<(), Error>;
+}
+
+/// Keeps the registration in a JSON file.
+///
+/// Saves go to a temporary file next to it that is synced and renamed over the old one, so a crash
+/// leaves either the old or the new state behind. On Unix the file is only readable by its owner,
+/// it holds the keys to decrypt every message; [`sealed`](Self::sealed) encrypts them on top.
+#[derive(Clone, Debug)]
+pub struct FileRegistrationStore {
+    path: PathBuf,
+    sealing_key: Option<Arc<SealingKey>>,
+}
+
+impl FileRegistrationStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            sealing_key: None,
+        }
+    }
+
+    /// Seal the registration's secrets with `key` when saving.
+    ///
+    /// A file saved without sealing still loads, and is sealed on the next save.
+    pub fn sealed(mut self, key: SealingKey) -> Self {
+        self.sealing_key = Some(Arc::new(key));
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        self.path.with_file_name(name)
+    }
+}
+
+impl RegistrationStore for FileRegistrationStore {
+    fn load(&self) -> Result<Option<StoredRegistration>, Error> {
+        let bytes = match std::fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::Storage("registration read", e)),
+        };
+
+        const KIND: &str = "stored registration";
+
+        let value: serde_json::Value =
+            serde_json::from_slice(&bytes).map_err(|e| Error::Json(KIND, e))?;
+        if value["registration"].get("secrets").is_none() {
+            return serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|e| Error::Json(KIND, e));
+        }
+
+        let Some(ref key) = self.sealing_key else {
+            return Err(Error::SealingKeyRequired);
+        };
+        let sealed: SealedStoredRegistration =
+            serde_json::from_slice(&bytes).map_err(|e| Error::Json(KIND, e))?;
+
+        Ok(Some(StoredRegistration {
+            registration: sealed.registration.open(key)?,
+            received_persistent_ids: sealed.received_persistent_ids,
+            apps: sealed
+                .apps
+                .iter()
+                .map(|app| app.open(key))
+                .collect::<Result<_, _>>()?,
+        }))
+    }
+
+    fn save(&self, state: &StoredRegistration) -> Result<(), Error> {
+        use std::io::Write;
+
+        let bytes = match self.sealing_key {
+            Some(ref key) => serde_json::to_vec_pretty(&SealedStoredRegistration {
+                registration: state.registration.seal(key)?,
+                received_persistent_ids: state.received_persistent_ids.clone(),
+                apps: state
+                    .apps
+                    .iter()
+                    .map(|app| app.seal(key))
+                    .collect::<Result<_, _>>()?,
+            }),
+            None => serde_json::to_vec_pretty(state),
+        }
+        .map_err(|e| Error::Json("stored registration", e))?;
+        let temporary_path = self.temporary_path();
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        // a leftover from an interrupted save could have been created with other permissions
+        let _ = std::fs::remove_file(&temporary_path);
+        let mut file = options
+            .open(&temporary_path)
+            .map_err(|e| Error::Storage("registration write", e))?;
+        file.write_all(&bytes)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| Error::Storage("registration write", e))?;
+        drop(file);
+
+        std::fs::rename(&temporary_path, &self.path)
+            .map_err(|e| Error::Storage("registration rename", e))?;
+
+        // the rename itself only survives a crash once the directory is synced
+        #[cfg(unix)]
+        if let Some(directory) = self.path.parent() {
+            let directory = if directory.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                directory
+            };
+            std::fs::File::open(directory)
+                .and_then(|directory| directory.sync_all())
+                .map_err(|e| Error::Storage("registration directory sync", e))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1101 @@
+//! In-process stand-in for the Google services, for integration tests that must not touch the
+//! network.
+//!
+//! [`FakeServer`] answers the check-in, `register3`, Firebase installation and FCM registration
+//! calls over plain HTTP, and runs a TLS MCS server that accepts logins, answers heartbeats and
+//! acks, and delivers the messages passed to [`FakeServer::push`].
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), fcm_push_listener::Error> {
+//! use fcm_push_listener::testing::FakeServer;
+//! use fcm_push_listener::{register, PushListener};
+//!
+//! let server = FakeServer::start().await.expect("fake server should start");
+//! let http = reqwest::Client::new();
+//! let registration = register(&http, &server.endpoints(), "app", "project", "key", None).await?;
+//!
+//! server.push(&registration.keys, b"hello")?;
+//!
+//! let mut listener = PushListener::new(registration, Vec::new())
+//!     .with_endpoints(server.endpoints())
+//!     .with_connection_options(server.connection_options());
+//! let message = listener.next().await?;
+//! assert_eq!(message.body, b"hello");
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::gcm::{contract, read_varint};
+use crate::mcs;
+use crate::push::{encode_frame, MessageTag};
+use crate::{
+    ConnectionOptions, ContentEncoding, EncryptedPayload, Endpoints, Error, TrustRoots, WebPushKeys,
+};
+use bytes::BytesMut;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+
+const MCS_VERSION: u8 = 41;
+const SELECTIVE_ACK_EXTENSION: i32 = 12;
+
+/// A registration the fake FCM registrations API has handed out
+#[derive(Clone, Debug)]
+pub struct FakeRegistration {
+    pub project_id: String,
+    pub fcm_token: String,
+
+    /// Push endpoint the client asked FCM to deliver to
+    pub endpoint: String,
+
+    /// URL safe base64, as sent by the client
+    pub auth: String,
+    pub p256dh: String,
+
+    /// VAPID key pushes to the endpoint must be signed with, if the client gave one
+    pub application_pub_key: Option<String>,
+}
+
+/// A Firebase installation the fake installations API has created
+#[derive(Clone, Debug)]
+pub struct FakeInstallation {
+    pub project_id: String,
+    pub fid: String,
+    pub refresh_token: String,
+
+    /// auth tokens issued for it, oldest first
+    pub auth_tokens: Vec<String>,
+}
+
+/// A web push request received on the fake push endpoint
+#[derive(Clone, Debug)]
+pub struct FakePushRequest {
+    /// GCM token from the endpoint path
+    pub gcm_token: String,
+
+    /// Request headers, names in lower case
+    pub headers: Vec<(String, String)>,
+
+    /// ID of the data message the request was delivered as
+    pub persistent_id: String,
+}
+
+#[derive(Default)]
+struct State {
+    /// scheme, host and port of the HTTP server, the audience of VAPID tokens
+    origin: String,
+    android_ids: Vec<i64>,
+    /// app ID and token of every live GCM registration
+    gcm_tokens: Vec<(String, String)>,
+    installations: Vec<FakeInstallation>,
+    registrations: Vec<FakeRegistration>,
+    push_requests: Vec<FakePushRequest>,
+    /// endpoints of deleted FCM registrations, which pushes find gone
+    expired_endpoints: Vec<String>,
+    logins: Vec<mcs::LoginRequest>,
+    acknowledged: Vec<String>,
+
+    /// HTTP requests still to be answered with 503, see [`FakeServer::fail_next`]
+    failures: usize,
+
+    /// heartbeat pings go unanswered, see [`FakeServer::ignore_pings`]
+    ignoring_pings: bool,
+
+    /// pushes the client hasn't acknowledged yet, sent again on every login
+    undelivered: Vec<mcs::DataMessageStanza>,
+    connections: Vec<mpsc::UnboundedSender<BytesMut>>,
+}
+
+type SharedState = Arc<Mutex<State>>;
+
+/// Local stand-in for the check-in, GCM, Firebase installation, FCM registration and MCS services.
+///
+/// The servers listen on `127.0.0.1` until the `FakeServer` is dropped. Point a client at them
+/// with [`FakeServer::endpoints`] and [`FakeServer::connection_options`].
+pub struct FakeServer {
+    http_address: SocketAddr,
+    mcs_address: SocketAddr,
+    certificate: CertificateDer<'static>,
+    state: SharedState,
+    tasks: Vec<tokio::task::JoinHandle<()>>,
+}
+
+impl FakeServer {
+    /// binds both servers to free ports on the loopback interface
+    pub async fn start() -> std::io::Result<Self> {
+        // Install the default crypto provider. If a different one is already registered, this
+        // will do nothing.
+        let _ = rustls::crypto::ring::default_provider().install_default();
+
+        let certified = rcgen::generate_simple_self_signed(vec![
+            "127.0.0.1".to_string(),
+            "localhost".to_string(),
+        ])
+        .map_err(std::io::Error::other)?;
+        let certificate = certified.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+        let tls_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate.clone()], key.into())
+            .map_err(std::io::Error::other)?;
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls_config));
+
+        let http = TcpListener::bind("127.0.0.1:0").await?;
+        let mcs = TcpListener::bind("127.0.0.1:0").await?;
+        let http_address = http.local_addr()?;
+        let mcs_address = mcs.local_addr()?;
+
+        let state = SharedState::new(Mutex::new(State {
+            origin: format!("http://{http_address}"),
+            ..Default::default()
+        }));
+        let tasks = vec![
+            tokio::spawn(accept_http(http, state.clone())),
+            tokio::spawn(accept_mcs(mcs, acceptor, state.clone())),
+        ];
+
+        Ok(Self {
+            http_address,
+            mcs_address,
+            certificate,
+            state,
+            tasks,
+        })
+    }
+
+    /// addresses of the fake services, to pass to `register` and the listener
+    pub fn endpoints(&self) -> Endpoints {
+        let base = format!("http://{}", self.http_address);
+        Endpoints {
+            checkin: format!("{base}/checkin"),
+            register: format!("{base}/c2dm/register3"),
+            installations: format!("{base}/firebaseinstallations/v1"),
+            fcm_registrations: format!("{base}/fcmregistrations/v1"),
+            fcm_send: format!("{base}/fcm/send"),
+            mcs_host: self.mcs_address.ip().to_string(),
+            mcs_port: self.mcs_address.port(),
+        }
+    }
+
+    /// connection options that trust the fake MCS server's self-signed certificate
+    pub fn connection_options(&self) -> ConnectionOptions {
+        ConnectionOptions {
+            roots: TrustRoots::ExtraOnly,
+            extra_roots: vec![self.certificate.clone()],
+            ..Default::default()
+        }
+    }
+
+    /// the MCS server certificate, to pin its key with [`ConnectionOptions::public_key_pin`]
+    pub fn certificate(&self) -> &[u8] {
+        &self.certificate
+    }
+
+    /// Encrypts `payload` for the given keys with the legacy `aesgcm` scheme, as FCM does, and
+    /// delivers it to every logged in client.
+    ///
+    /// When the keys belong to a registration made with this server, the message names its app ID
+    /// the way FCM does. The message is kept and delivered again on each login until a client acknowledges it.
+    /// Returns the message's persistent ID.
+    pub fn push(&self, keys: &WebPushKeys, payload: &[u8]) -> Result<String, Error> {
+        let payload = EncryptedPayload::encrypt(
+            &keys.public_key,
+            &keys.auth_secret,
+            payload,
+            ContentEncoding::AesGcm,
+        )?;
+
+        let mut state = self.state.lock().unwrap();
+        let app_id = state.app_id_for_keys(&keys.public_key);
+        let persistent_id = new_persistent_id();
+        Ok(state.deliver(payload.data_message_stanza(&persistent_id), app_id))
+    }
+
+    /// delivers an already encrypted payload like [`push`](Self::push), without an app ID, and
+    /// returns its persistent ID
+    pub fn push_payload(&self, payload: &EncryptedPayload) -> String {
+        let stanza = payload.data_message_stanza(&new_persistent_id());
+        self.state.lock().unwrap().deliver(stanza, None)
+    }
+
+    /// delivers a message without an encrypted payload, only the given app data, to the
+    /// registration holding `keys` like [`push`](Self::push) and returns its persistent ID
+    pub fn push_app_data(&self, keys: &WebPushKeys, app_data: &[(&str, &str)]) -> String {
+        let persistent_id = new_persistent_id();
+        let stanza = mcs::DataMessageStanza {
+            id: Some(persistent_id.clone()),
+            category: "org.chromium.linux".into(),
+            persistent_id: Some(persistent_id),
+            app_data: app_data
+                .iter()
+                .map(|&(key, value)| mcs::AppData {
+                    key: key.into(),
+                    value: value.into(),
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let app_id = state.app_id_for_keys(&keys.public_key);
+        state.deliver(stanza, app_id)
+    }
+
+    /// android IDs issued by the fake check-in
+    pub fn android_ids(&self) -> Vec<i64> {
+        self.state.lock().unwrap().android_ids.clone()
+    }
+
+    /// tokens issued by the fake `register3` and not deleted since
+    pub fn gcm_tokens(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .gcm_tokens
+            .iter()
+            .map(|(_, token)| token.clone())
+            .collect()
+    }
+
+    /// installations created and not deleted since
+    pub fn installations(&self) -> Vec<FakeInstallation> {
+        self.state.lock().unwrap().installations.clone()
+    }
+
+    /// FCM registrations made and not deleted since
+    pub fn registrations(&self) -> Vec<FakeRegistration> {
+        self.state.lock().unwrap().registrations.clone()
+    }
+
+    /// web push requests the fake push endpoint has accepted, oldest first
+    pub fn push_requests(&self) -> Vec<FakePushRequest> {
+        self.state.lock().unwrap().push_requests.clone()
+    }
+
+    /// every login request the MCS server has accepted, oldest first
+    pub fn logins(&self) -> Vec<mcs::LoginRequest> {
+        self.state.lock().unwrap().logins.clone()
+    }
+
+    /// persistent IDs the clients have acknowledged, through a selective ack or a login
+    pub fn acknowledged(&self) -> Vec<String> {
+        self.state.lock().unwrap().acknowledged.clone()
+    }
+
+    /// answers the next `count` HTTP requests with 503 Service Unavailable, whatever they ask for
+    pub fn fail_next(&self, count: usize) {
+        self.state.lock().unwrap().failures = count;
+    }
+
+    /// stops answering heartbeat pings, or starts again, so clients see their connection go stale
+    pub fn ignore_pings(&self, ignore: bool) {
+        self.state.lock().unwrap().ignoring_pings = ignore;
+    }
+
+    /// drops every open MCS connection, clients see the stream end
+    pub fn disconnect(&self) {
+        self.state.lock().unwrap().connections.clear();
+    }
+}
+
+impl Drop for FakeServer {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+fn new_persistent_id() -> String {
+    format!("0:{}", uuid::Uuid::new_v4().simple())
+}
+
+impl State {
+    /// queues the message for the clients and sends it to the connected ones, returns its
+    /// persistent ID
+    fn deliver(&mut self, mut stanza: mcs::DataMessageStanza, app_id: Option<String>) -> String {
+        let persistent_id = stanza.persistent_id.clone().unwrap_or_default();
+        stanza.from = "fake-sender".into();
+        if let Some(app_id) = app_id {
+            stanza.app_data.push(mcs::AppData {
+                key: "subtype".into(),
+                value: app_id,
+            });
+        }
+
+        let frame = encode_frame(MessageTag::DataMessageStanza, &stanza);
+        self.connections
+            .retain(|connection| connection.send(frame.clone()).is_ok());
+        self.undelivered.push(stanza);
+
+        persistent_id
+    }
+
+    /// app ID the GCM token was issued for
+    fn app_id_for_token(&self, gcm_token: &str) -> Option<String> {
+        self.gcm_tokens
+            .iter()
+            .find(|(_, token)| token == gcm_token)
+            .map(|(app_id, _)| app_id.clone())
+    }
+
+    /// app ID of the FCM registration made for the given public key
+    fn app_id_for_keys(&self, public_key: &[u8]) -> Option<String> {
+        use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        use base64::Engine;
+
+        let registration = self.registrations.iter().find(|registration| {
+            URL_SAFE_NO_PAD
+                .decode(registration.p256dh.trim_end_matches('='))
+                .is_ok_and(|key| key == public_key)
+        })?;
+        let (_, gcm_token) = registration.endpoint.rsplit_once('/')?;
+        self.app_id_for_token(gcm_token)
+    }
+
+    fn acknowledge(&mut self, persistent_ids: &[String]) {
+        self.undelivered.retain(|stanza| {
+            stanza
+                .persistent_id
+                .as_ref()
+                .is_none_or(|id| !persistent_ids.contains(id))
+        });
+
+        for id in persistent_ids {
+            if !self.acknowledged.contains(id) {
+                self.acknowledged.push(id.clone());
+            }
+        }
+    }
+}
+
+async fn accept_http(listener: TcpListener, state: SharedState) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_http(stream, state).await {
+                log::debug!("Fake HTTP server connection failed: {e}");
+            }
+        });
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+
+    /// names in lower case
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct HttpResponse {
+    status: &'static str,
+    content_type: &'static str,
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn ok(content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status: "200 OK",
+            content_type,
+            location: None,
+            body: body.into(),
+        }
+    }
+
+    fn created(location: String) -> Self {
+        Self {
+            status: "201 Created",
+            content_type: "text/plain",
+            location: Some(location),
+            body: Vec::new(),
+        }
+    }
+
+    fn bad_request(reason: impl Into<String>) -> Self {
+        Self {
+            status: "400 Bad Request",
+            content_type: "text/plain",
+            location: None,
+            body: reason.into().into_bytes(),
+        }
+    }
+
+    /// a 401 with the error body Google APIs answer with
+    fn unauthorized() -> Self {
+        let body = serde_json::json!({
+            "error": {
+                "code": 401,
+                "message": "Request is missing required authentication credential.",
+                "status": "UNAUTHENTICATED",
+            },
+        });
+
+        Self {
+            status: "401 Unauthorized",
+            content_type: "application/json",
+            location: None,
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn unavailable() -> Self {
+        let body = serde_json::json!({
+            "error": {
+                "code": 503,
+                "message": "The service is currently unavailable.",
+                "status": "UNAVAILABLE",
+            },
+        });
+
+        Self {
+            status: "503 Service Unavailable",
+            content_type: "application/json",
+            location: None,
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn forbidden(reason: impl Into<String>) -> Self {
+        Self {
+            status: "403 Forbidden",
+            content_type: "text/plain",
+            location: None,
+            body: reason.into().into_bytes(),
+        }
+    }
+
+    fn gone() -> Self {
+        Self {
+            status: "410 Gone",
+            content_type: "text/plain",
+            location: None,
+            body: b"push subscription has unsubscribed or expired".to_vec(),
+        }
+    }
+
+    fn not_found() -> Self {
+        Self {
+            status: "404 Not Found",
+            content_type: "text/plain",
+            location: None,
+            body: b"not found".to_vec(),
+        }
+    }
+}
+
+/// handles a single HTTP/1.1 request and closes the connection
+async fn serve_http(mut stream: TcpStream, state: SharedState) -> std::io::Result<()> {
+    let mut buffer = Vec::new();
+    let header_end = loop {
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let mut request = HttpRequest {
+        method,
+        path,
+        headers,
+        body: buffer.split_off(header_end),
+    };
+    let content_length = request
+        .header("content-length")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    if request.body.len() < content_length {
+        let mut rest = vec![0; content_length - request.body.len()];
+        stream.read_exact(&mut rest).await?;
+        request.body.extend(rest);
+    }
+
+    let response = route(&state, &request);
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    if let Some(ref location) = response.location {
+        head.push_str(&format!("Location: {location}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await
+}
+
+fn route(state: &SharedState, request: &HttpRequest) -> HttpResponse {
+    let path = request.path.as_str();
+    let body = request.body.as_slice();
+    {
+        let mut state = state.lock().unwrap();
+        if state.failures > 0 {
+            state.failures -= 1;
+            return HttpResponse::unavailable();
+        }
+    }
+
+    if request.method == "DELETE" {
+        return delete(state, request);
+    }
+
+    if path == "/checkin" {
+        return checkin(state, body);
+    }
+
+    if path == "/c2dm/register3" {
+        return register3(state, body);
+    }
+
+    if let Some(gcm_token) = path.strip_prefix("/fcm/send/") {
+        return web_push(state, gcm_token, request);
+    }
+
+    if let Some(rest) = path.strip_prefix("/firebaseinstallations/v1/projects/") {
+        if let Some(project) = rest.strip_suffix("/installations") {
+            return installation(state, project, body);
+        }
+
+        if let Some((project, fid)) = rest
+            .strip_suffix("/authTokens:generate")
+            .and_then(|rest| rest.split_once("/installations/"))
+        {
+            return generate_auth_token(state, project, fid, request);
+        }
+    }
+
+    if let Some(project) = path
+        .strip_prefix("/fcmregistrations/v1/projects/")
+        .and_then(|rest| rest.strip_suffix("/registrations"))
+    {
+        return fcm_registration(state, project, body);
+    }
+
+    HttpResponse::not_found()
+}
+
+fn checkin(state: &SharedState, body: &[u8]) -> HttpResponse {
+    use prost::Message;
+
+    let request = match contract::AndroidCheckinRequest::decode(body) {
+        Ok(request) => request,
+        Err(e) => return HttpResponse::bad_request(e.to_string()),
+    };
+
+    // returning devices keep their identity, new ones get a fresh one
+    let android_id = request
+        .id
+        .unwrap_or_else(|| rand::random_range(1..i64::MAX));
+    let security_token = request
+        .security_token
+        .unwrap_or_else(|| rand::random_range(1..u64::MAX));
+    state.lock().unwrap().android_ids.push(android_id);
+
+    let response = contract::AndroidCheckinResponse {
+        stats_ok: true,
+        android_id: Some(android_id as u64),
+        security_token: Some(security_token),
+        ..Default::default()
+    };
+
+    HttpResponse::ok("application/x-protobuf", response.encode_to_vec())
+}
+
+fn register3(state: &SharedState, body: &[u8]) -> HttpResponse {
+    // borrow the URL parser to decode the form
+    let form = format!("http://form/?{}", String::from_utf8_lossy(body));
+    let Ok(form) = url::Url::parse(&form) else {
+        return HttpResponse::bad_request("malformed form");
+    };
+    let field = |name: &str| {
+        form.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+
+    let Some(app_id) = field("X-subtype").filter(|app_id| !app_id.is_empty()) else {
+        return HttpResponse::ok("text/plain", "Error=INVALID_PARAMETERS");
+    };
+
+    let mut state = state.lock().unwrap();
+    if field("delete").as_deref() == Some("true") {
+        state.gcm_tokens.retain(|(id, _)| *id != app_id);
+        return HttpResponse::ok("text/plain", format!("deleted={app_id}"));
+    }
+
+    let token = format!("fake-gcm-{}", uuid::Uuid::new_v4().simple());
+    state.gcm_tokens.push((app_id, token.clone()));
+    HttpResponse::ok("text/plain", format!("token={token}"))
+}
+
+/// deletes an FCM registration or a Firebase installation
+fn delete(state: &SharedState, request: &HttpRequest) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+
+    if let Some((project, fcm_token)) = request
+        .path
+        .strip_prefix("/fcmregistrations/v1/projects/")
+        .and_then(|rest| rest.split_once("/registrations/"))
+    {
+        let auth_token = request.header("x-goog-firebase-installations-auth");
+        let authorized = state.installations.iter().any(|installation| {
+            installation.project_id == project
+                && installation
+                    .auth_tokens
+                    .iter()
+                    .any(|token| Some(token.as_str()) == auth_token)
+        });
+        if !authorized {
+            return HttpResponse::unauthorized();
+        }
+
+        let Some(index) = state.registrations.iter().position(|registration| {
+            registration.project_id == project && registration.fcm_token == fcm_token
+        }) else {
+            return HttpResponse::not_found();
+        };
+        let registration = state.registrations.remove(index);
+        state.expired_endpoints.push(registration.endpoint);
+
+        return HttpResponse::ok("application/json", "{}");
+    }
+
+    if let Some((project, fid)) = request
+        .path
+        .strip_prefix("/firebaseinstallations/v1/projects/")
+        .and_then(|rest| rest.split_once("/installations/"))
+    {
+        let refresh_token = request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("FIS_v2 "));
+        let count = state.installations.len();
+        state.installations.retain(|installation| {
+            installation.project_id != project
+                || installation.fid != fid
+                || Some(installation.refresh_token.as_str()) != refresh_token
+        });
+        if state.installations.len() == count {
+            return HttpResponse::unauthorized();
+        }
+
+        return HttpResponse::ok("application/json", "{}");
+    }
+
+    HttpResponse::not_found()
+}
+
+fn installation(state: &SharedState, project_id: &str, body: &[u8]) -> HttpResponse {
+    let request: serde_json::Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return HttpResponse::bad_request(e.to_string()),
+    };
+
+    let installation = FakeInstallation {
+        project_id: project_id.to_string(),
+        fid: request["fid"]
+            .as_str()
+            .map(String::from)
+            .unwrap_or_else(|| format!("f{}", uuid::Uuid::new_v4().simple())),
+        refresh_token: format!("fake-refresh-{}", uuid::Uuid::new_v4().simple()),
+        auth_tokens: vec![format!("fake-auth-{}", uuid::Uuid::new_v4().simple())],
+    };
+    let body = serde_json::json!({
+        "name": format!("projects/{project_id}/installations/{}", installation.fid),
+        "fid": installation.fid,
+        "refreshToken": installation.refresh_token,
+        "authToken": {
+            "token": installation.auth_tokens[0],
+            "expiresIn": "604800s",
+        },
+    });
+    state.lock().unwrap().installations.push(installation);
+
+    HttpResponse::ok("application/json", body.to_string())
+}
+
+fn generate_auth_token(
+    state: &SharedState,
+    project_id: &str,
+    fid: &str,
+    request: &HttpRequest,
+) -> HttpResponse {
+    let refresh_token = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("FIS_v2 "));
+
+    let mut state = state.lock().unwrap();
+    let installation = state.installations.iter_mut().find(|installation| {
+        installation.project_id == project_id
+            && installation.fid == fid
+            && Some(installation.refresh_token.as_str()) == refresh_token
+    });
+    let Some(installation) = installation else {
+        return HttpResponse::unauthorized();
+    };
+
+    let token = format!("fake-auth-{}", uuid::Uuid::new_v4().simple());
+    installation.auth_tokens.push(token.clone());
+    let body = serde_json::json!({
+        "token": token,
+        "expiresIn": "604800s",
+    });
+
+    HttpResponse::ok("application/json", body.to_string())
+}
+
+fn fcm_registration(state: &SharedState, project_id: &str, body: &[u8]) -> HttpResponse {
+    let request: serde_json::Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return HttpResponse::bad_request(e.to_string()),
+    };
+
+    let web = &request["web"];
+    let field = |name: &str| web[name].as_str().unwrap_or_default().to_string();
+    let registration = FakeRegistration {
+        project_id: project_id.to_string(),
+        fcm_token: format!("fake-fcm-{}", uuid::Uuid::new_v4().simple()),
+        endpoint: field("endpoint"),
+        auth: field("auth"),
+        p256dh: field("p256dh"),
+        application_pub_key: web["applicationPubKey"].as_str().map(String::from),
+    };
+    if registration.endpoint.is_empty() || registration.p256dh.is_empty() {
+        return HttpResponse::bad_request("missing web push subscription");
+    }
+
+    let body = serde_json::json!({
+        "name": format!("projects/{project_id}/registrations/{}", registration.fcm_token),
+        "token": registration.fcm_token,
+        "web": {
+            "endpoint": registration.endpoint,
+            "auth": registration.auth,
+            "p256dh": registration.p256dh,
+        },
+    });
+    state.lock().unwrap().registrations.push(registration);
+
+    HttpResponse::ok("application/json", body.to_string())
+}
+
+/// relays a web push to the MCS clients, the way FCM turns it into a data message
+fn web_push(state: &SharedState, gcm_token: &str, request: &HttpRequest) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    let path = format!("/fcm/send/{gcm_token}");
+    let Some(registration) = state
+        .registrations
+        .iter()
+        .find(|registration| registration.endpoint.ends_with(&path))
+    else {
+        if state
+            .expired_endpoints
+            .iter()
+            .any(|endpoint| endpoint.ends_with(&path))
+        {
+            return HttpResponse::gone();
+        }
+        return HttpResponse::not_found();
+    };
+
+    if let Some(ref application_pub_key) = registration.application_pub_key {
+        if let Err(reason) = check_vapid(request, application_pub_key, &state.origin) {
+            return HttpResponse::forbidden(reason);
+        }
+    }
+
+    if request.header("ttl").is_none() {
+        return HttpResponse::bad_request("missing TTL header");
+    }
+
+    let encoding = match request.header("content-encoding") {
+        Some(value) if value.eq_ignore_ascii_case("aes128gcm") => ContentEncoding::Aes128Gcm,
+        Some(value) if value.eq_ignore_ascii_case("aesgcm") => ContentEncoding::AesGcm,
+        _ => return HttpResponse::bad_request("unsupported content encoding"),
+    };
+
+    let payload = EncryptedPayload {
+        encoding,
+        body: request.body.clone(),
+        crypto_key: request.header("crypto-key").map(String::from),
+        encryption: request.header("encryption").map(String::from),
+    };
+
+    let app_id = state.app_id_for_token(gcm_token);
+    let persistent_id = state.deliver(payload.data_message_stanza(&new_persistent_id()), app_id);
+    state.push_requests.push(FakePushRequest {
+        gcm_token: gcm_token.to_string(),
+        headers: request.headers.clone(),
+        persistent_id: persistent_id.clone(),
+    });
+
+    HttpResponse::created(format!("/fcm/send/messages/{persistent_id}"))
+}
+
+/// Checks the VAPID token of a push to a subscription made with `application_pub_key`, in
+/// either the RFC 8292 `vapid` scheme or the draft `WebPush` one.
+fn check_vapid(
+    request: &HttpRequest,
+    application_pub_key: &str,
+    origin: &str,
+) -> Result<(), String> {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use openssl::bn::{BigNum, BigNumContext};
+    use openssl::ec::{EcGroup, EcKey, EcPoint};
+    use openssl::ecdsa::EcdsaSig;
+    use openssl::nid::Nid;
+
+    let authorization = request
+        .header("authorization")
+        .ok_or("missing VAPID authorization")?;
+    let (token, key) = if let Some(parameters) = authorization.strip_prefix("vapid ") {
+        let parameter = |name: &str| {
+            parameters
+                .split(',')
+                .find_map(|parameter| parameter.trim().strip_prefix(name))
+        };
+        (parameter("t="), parameter("k="))
+    } else if let Some(token) = authorization.strip_prefix("WebPush ") {
+        let key = request.header("crypto-key").and_then(|value| {
+            value
+                .split([';', ','])
+                .find_map(|parameter| parameter.trim().strip_prefix("p256ecdsa="))
+        });
+        (Some(token), key)
+    } else {
+        return Err("unknown authorization scheme".into());
+    };
+    let (Some(token), Some(key)) = (token, key) else {
+        return Err("incomplete VAPID authorization".into());
+    };
+    if key.trim_end_matches('=') != application_pub_key.trim_end_matches('=') {
+        return Err("VAPID key doesn't match the subscription".into());
+    }
+
+    let (signing_input, signature) = token.rsplit_once('.').ok_or("malformed VAPID token")?;
+    let (_, claims) = signing_input
+        .split_once('.')
+        .ok_or("malformed VAPID token")?;
+    let decode = |value: &str| URL_SAFE_NO_PAD.decode(value.trim_end_matches('='));
+
+    let verified = (|| {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let mut context = BigNumContext::new()?;
+        let point = EcPoint::from_bytes(&group, &decode(key).unwrap_or_default(), &mut context)?;
+        let public_key = EcKey::from_public_key(&group, &point)?;
+
+        let signature = decode(signature).unwrap_or_default();
+        if signature.len() != 64 {
+            return Ok(false);
+        }
+        let signature = EcdsaSig::from_private_components(
+            BigNum::from_slice(&signature[..32])?,
+            BigNum::from_slice(&signature[32..])?,
+        )?;
+        signature.verify(&openssl::sha::sha256(signing_input.as_bytes()), &public_key)
+    })();
+    if !verified.unwrap_or(false) {
+        return Err("VAPID token signature doesn't verify".into());
+    }
+
+    let claims: serde_json::Value = decode(claims)
+        .ok()
+        .and_then(|claims| serde_json::from_slice(&claims).ok())
+        .ok_or("malformed VAPID claims")?;
+    if claims["aud"].as_str() != Some(origin) {
+        return Err("VAPID token is for another audience".into());
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    match claims["exp"].as_u64() {
+        Some(expires) if expires > now && expires <= now + 24 * 60 * 60 => {}
+        _ => return Err("VAPID token is expired or valid for over 24 hours".into()),
+    }
+    let subject = claims["sub"].as_str().unwrap_or_default();
+    if !subject.starts_with("mailto:") && !subject.starts_with("https:") {
+        return Err("VAPID subject is not a mailto: or https: URL".into());
+    }
+
+    Ok(())
+}
+
+async fn accept_mcs(
+    listener: TcpListener,
+    acceptor: tokio_rustls::TlsAcceptor,
+    state: SharedState,
+) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let acceptor = acceptor.clone();
+        let state = state.clone();
+        tokio::spawn(async move {
+            let result = match acceptor.accept(stream).await {
+                Ok(stream) => serve_mcs(stream, state).await,
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = result {
+                log::debug!("Fake MCS connection failed: {e}");
+            }
+        });
+    }
+}
+
+async fn read_frame<R>(reader: &mut R) -> std::io::Result<(u8, Vec<u8>)>
+where
+    R: AsyncRead + Unpin,
+{
+    let tag = reader.read_u8().await?;
+    let size = read_varint(reader).await?;
+    let mut bytes = vec![0; size];
+    reader.read_exact(&mut bytes).await?;
+    Ok((tag, bytes))
+}
+
+fn invalid_data(e: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+}
+
+async fn serve_mcs(
+    stream: tokio_rustls::server::TlsStream<TcpStream>,
+    state: SharedState,
+) -> std::io::Result<()> {
+    use prost::Message;
+
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    reader.read_u8().await?;
+    let (tag, bytes) = read_frame(&mut reader).await?;
+    if tag != MessageTag::LoginRequest as u8 {
+        return Err(invalid_data("expected a login request"));
+    }
+    let login = mcs::LoginRequest::decode(bytes.as_slice()).map_err(invalid_data)?;
+
+    let response = mcs::LoginResponse {
+        id: login.id.clone(),
+        stream_id: Some(1),
+        last_stream_id_received: Some(1),
+        ..Default::default()
+    };
+    let mut login_bytes = BytesMut::from(&[MCS_VERSION][..]);
+    login_bytes.extend(encode_frame(MessageTag::LoginResponse, &response));
+    writer.write_all(&login_bytes).await?;
+
+    // the writer runs on its own task so pushes can be delivered while we wait for the client
+    // only the state holds the sender, so dropping it there ends the connection
+    let (sender, mut receiver) = mpsc::unbounded_channel::<BytesMut>();
+    let reply = sender.downgrade();
+    {
+        let mut state = state.lock().unwrap();
+        state.acknowledge(&login.received_persistent_id);
+        for stanza in &state.undelivered {
+            let _ = sender.send(encode_frame(MessageTag::DataMessageStanza, stanza));
+        }
+        state.connections.push(sender);
+        state.logins.push(login);
+    }
+
+    let mut write = tokio::spawn(async move {
+        while let Some(frame) = receiver.recv().await {
+            writer.write_all(&frame).await?;
+        }
+
+        writer.shutdown().await
+    });
+
+    let result = read_mcs(&mut reader, reply, &state, &mut write).await;
+    write.abort();
+    result
+}
+
+async fn read_mcs<R>(
+    reader: &mut R,
+    reply: mpsc::WeakUnboundedSender<BytesMut>,
+    state: &SharedState,
+    write: &mut tokio::task::JoinHandle<std::io::Result<()>>,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    use prost::Message;
+
+    let send = |frame: BytesMut| {
+        if let Some(sender) = reply.upgrade() {
+            let _ = sender.send(frame);
+        }
+    };
+
+    loop {
+        let (tag, bytes) = tokio::select! {
+            frame = read_frame(reader) => frame?,
+            _ = &mut *write => return Ok(()),
+        };
+
+        match MessageTag::try_from(tag) {
+            Ok(MessageTag::HeartbeatPing) => {
+                if state.lock().unwrap().ignoring_pings {
+                    continue;
+                }
+                send(encode_frame(
+                    MessageTag::HeartbeatAck,
+                    &mcs::HeartbeatAck::default(),
+                ));
+            }
+            Ok(MessageTag::IqStanza) => {
+                let iq = mcs::IqStanza::decode(bytes.as_slice()).map_err(invalid_data)?;
+                let Some(ref extension) = iq.extension else {
+                    continue;
+                };
+                if extension.id != SELECTIVE_ACK_EXTENSION {
+                    continue;
+                }
+
+                let ack =
+                    mcs::SelectiveAck::decode(extension.data.as_slice()).map_err(invalid_data)?;
+                state.lock().unwrap().acknowledge(&ack.id);
+
+                let result = mcs::IqStanza {
+                    r#type: mcs::iq_stanza::IqType::Result as i32,
+                    id: iq.id,
+                    ..Default::default()
+                };
+                send(encode_frame(MessageTag::IqStanza, &result));
+            }
+            Ok(MessageTag::Close) => return Ok(()),
+            _ => {}
+        }
+    }
+}
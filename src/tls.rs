@@ -0,0 +1,326 @@
+use crate::{ConnectionOptions, Error};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::sync::Arc;
+
+/// Where the trust anchors for the MCS server certificate come from
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum TrustRoots {
+    /// The Mozilla roots compiled into the `webpki-roots` crate
+    #[default]
+    WebPki,
+    /// The operating system's root store, which also holds CAs installed by administrators
+    Native,
+    /// Only [`ConnectionOptions::extra_roots`]
+    ExtraOnly,
+}
+
+/// Builds the TLS settings described by `options`.
+pub(crate) fn client_config(options: &ConnectionOptions) -> Result<Arc<ClientConfig>, Error> {
+    if let Some(ref config) = options.tls_config {
+        return Ok(config.clone());
+    }
+
+    // Install the default crypto provider. If a different one is already registered, this
+    // will do nothing.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    let mut roots = RootCertStore::empty();
+    match options.roots {
+        TrustRoots::WebPki => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        TrustRoots::Native => {
+            let native = rustls_native_certs::load_native_certs();
+            for e in &native.errors {
+                log::warn!("Failed to load native root certificates: {e}");
+            }
+
+            let (added, ignored) = roots.add_parsable_certificates(native.certs);
+            if added == 0 {
+                return Err(Error::TlsConfig(
+                    "no usable native root certificates".into(),
+                ));
+            }
+            if ignored > 0 {
+                log::debug!("Ignored {ignored} unparsable native root certificates");
+            }
+        }
+        TrustRoots::ExtraOnly => {}
+    }
+
+    for root in &options.extra_roots {
+        roots
+            .add(root.clone())
+            .map_err(|e| Error::TlsConfig(format!("invalid extra root certificate: {e}")))?;
+    }
+
+    if options.pinned_public_keys.is_empty() {
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        return Ok(Arc::new(config));
+    }
+
+    let verifier = PinnedVerifier::new(roots, options.pinned_public_keys.clone())?;
+    let config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+/// SHA-256 of the DER encoded SubjectPublicKeyInfo of a DER certificate, the pin format of
+/// [`ConnectionOptions::pinned_public_keys`]
+pub(crate) fn public_key_pin(certificate: &[u8]) -> Result<[u8; 32], Error> {
+    let certificate = CertificateDer::from(certificate);
+    let certificate = webpki::EndEntityCert::try_from(&certificate)
+        .map_err(|e| Error::TlsConfig(format!("unreadable certificate: {e}")))?;
+
+    Ok(spki_pin(&certificate.subject_public_key_info()))
+}
+
+fn spki_pin(spki: &[u8]) -> [u8; 32] {
+    openssl::sha::sha256(spki)
+}
+
+/// Verifies the chain as usual, then requires the path to a trust anchor to carry a pinned key.
+///
+/// Only certificates on a verified path count: a server can send any certificate along, and one
+/// that doesn't take part in the chain proves nothing.
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    anchors: Vec<rustls::pki_types::TrustAnchor<'static>>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl PinnedVerifier {
+    fn new(roots: RootCertStore, pins: Vec<[u8; 32]>) -> Result<Self, Error> {
+        let anchors = roots.roots.clone();
+        let inner = WebPkiServerVerifier::builder(Arc::new(roots))
+            .build()
+            .map_err(|e| Error::TlsConfig(e.to_string()))?;
+
+        Ok(Self {
+            inner,
+            anchors,
+            pins,
+        })
+    }
+
+    fn is_pinned(&self, spki: &[u8]) -> bool {
+        self.pins.contains(&spki_pin(spki))
+    }
+
+    /// whether a path from `end_entity` to a trust anchor carries a pinned key
+    fn pinned_path(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> bool {
+        let Ok(end_entity) = webpki::EndEntityCert::try_from(end_entity) else {
+            return false;
+        };
+
+        // called for every valid path, rejecting one makes webpki try the next
+        let check_pins = |path: &webpki::VerifiedPath<'_>| {
+            let pinned = self.is_pinned(&path.end_entity().subject_public_key_info())
+                || path
+                    .intermediate_certificates()
+                    .any(|certificate| self.is_pinned(&certificate.subject_public_key_info()))
+                || self.is_pinned(&anchor_spki(path.anchor()));
+            if pinned {
+                Ok(())
+            } else {
+                Err(webpki::Error::UnknownIssuer)
+            }
+        };
+
+        end_entity
+            .verify_for_usage(
+                rustls::crypto::ring::default_provider()
+                    .signature_verification_algorithms
+                    .all,
+                &self.anchors,
+                intermediates,
+                now,
+                webpki::KeyUsage::server_auth(),
+                None,
+                Some(&check_pins),
+            )
+            .is_ok()
+    }
+}
+
+/// a trust anchor's SubjectPublicKeyInfo with the SEQUENCE header it is stored without
+fn anchor_spki(anchor: &rustls::pki_types::TrustAnchor<'_>) -> Vec<u8> {
+    let contents: &[u8] = anchor.subject_public_key_info.as_ref();
+    let length = contents.len();
+
+    let mut spki = vec![0x30];
+    if length < 0x80 {
+        spki.push(length as u8);
+    } else {
+        let bytes = length.to_be_bytes();
+        let significant = &bytes[bytes.iter().take_while(|&&byte| byte == 0).count()..];
+        spki.push(0x80 | significant.len() as u8);
+        spki.extend_from_slice(significant);
+    }
+    spki.extend_from_slice(contents);
+    spki
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        if !self.pinned_path(end_entity, intermediates, now) {
+            return Err(rustls::Error::General(
+                "no certificate in the verified chain has a pinned public key".into(),
+            ));
+        }
+
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+    struct Ca {
+        certificate: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    fn ca(name: &str) -> Ca {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let certificate = params.self_signed(&key).unwrap();
+        Ca { certificate, key }
+    }
+
+    fn leaf(issuer: &Ca) -> CertificateDer<'static> {
+        let key = KeyPair::generate().unwrap();
+        CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&key, &issuer.certificate, &issuer.key)
+            .unwrap()
+            .der()
+            .clone()
+    }
+
+    fn verifier(root: &Ca, pins: Vec<[u8; 32]>) -> PinnedVerifier {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let mut roots = RootCertStore::empty();
+        roots.add(root.certificate.der().clone()).unwrap();
+        PinnedVerifier::new(roots, pins).unwrap()
+    }
+
+    fn verify(
+        verifier: &PinnedVerifier,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            &ServerName::try_from("localhost").unwrap(),
+            &[],
+            UnixTime::now(),
+        )
+    }
+
+    fn pin(ca: &Ca) -> [u8; 32] {
+        public_key_pin(ca.certificate.der()).unwrap()
+    }
+
+    #[test]
+    fn pinned_root_is_accepted() {
+        let root = ca("root");
+        let verifier = verifier(&root, vec![pin(&root)]);
+
+        assert!(verify(&verifier, &leaf(&root), &[]).is_ok());
+    }
+
+    #[test]
+    fn pinned_intermediate_is_accepted() {
+        let root = ca("root");
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "intermediate");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let certificate = params
+            .signed_by(&key, &root.certificate, &root.key)
+            .unwrap();
+        let intermediate = Ca { certificate, key };
+        let verifier = verifier(&root, vec![pin(&intermediate)]);
+
+        let chain = [intermediate.certificate.der().clone()];
+        assert!(verify(&verifier, &leaf(&intermediate), &chain).is_ok());
+    }
+
+    #[test]
+    fn unpinned_chain_is_rejected() {
+        let root = ca("root");
+        let verifier = verifier(&root, vec![pin(&ca("other"))]);
+
+        assert!(verify(&verifier, &leaf(&root), &[]).is_err());
+    }
+
+    #[test]
+    fn unused_certificate_with_pinned_key_is_rejected() {
+        let root = ca("root");
+        let pinned = ca("pinned");
+        let verifier = verifier(&root, vec![pin(&pinned)]);
+
+        // valid chain to the root, plus a certificate with the pinned key that isn't part of it
+        let appended = [pinned.certificate.der().clone()];
+        assert!(verify(&verifier, &leaf(&root), &appended).is_err());
+    }
+}
@@ -0,0 +1,592 @@
+use fcm_push_listener::testing::FakeServer;
+use fcm_push_listener::{
+    register, register_with_retry, Backoff, BoxFuture, ConnectionOptions, ContentEncoding,
+    DataMessage, EncryptedPayload, Error, FileRegistrationStore, HttpRequest, HttpResponse,
+    HttpTransport, PushListener, PushOptions, PushSender, Registration, RegistrationStore,
+    RetryPolicy, StoredRegistration, TransportError, VapidKeys,
+};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+async fn registered() -> (FakeServer, Registration) {
+    let server = FakeServer::start().await.expect("fake server should start");
+    let http = reqwest::Client::new();
+    let registration = register(&http, &server.endpoints(), "app", "project", "key", None)
+        .await
+        .expect("registration should succeed");
+    (server, registration)
+}
+
+fn listener(server: &FakeServer, registration: Registration) -> PushListener {
+    PushListener::new(registration, Vec::new())
+        .with_endpoints(server.endpoints())
+        .with_connection_options(server.connection_options())
+        .with_backoff(Backoff {
+            initial: Duration::from_millis(10),
+            ..Default::default()
+        })
+}
+
+/// the next message, reconnecting through the errors of a dropped connection
+async fn next_message(listener: &mut PushListener) -> DataMessage {
+    let receive = async {
+        loop {
+            match listener.next().await {
+                Ok(message) => return message,
+                Err(e) => eprintln!("reconnecting after: {e}"),
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), receive)
+        .await
+        .expect("a message should arrive")
+}
+
+async fn wait_for_ack(server: &FakeServer, persistent_id: &str) {
+    let acknowledged = async {
+        while !server.acknowledged().iter().any(|id| id == persistent_id) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), acknowledged)
+        .await
+        .expect("the message should be acknowledged");
+}
+
+#[tokio::test]
+async fn receives_and_acknowledges_a_push() {
+    let (server, registration) = registered().await;
+    let persistent_id = server.push(&registration.keys, b"hello").unwrap();
+
+    let mut listener = listener(&server, registration);
+    let message = next_message(&mut listener).await;
+
+    assert_eq!(message.body, b"hello");
+    assert_eq!(
+        message.persistent_id.as_deref(),
+        Some(persistent_id.as_str())
+    );
+    wait_for_ack(&server, &persistent_id).await;
+}
+
+#[tokio::test]
+async fn reconnects_after_disconnect() {
+    let (server, registration) = registered().await;
+    let keys = registration.keys.clone();
+    let mut listener = listener(&server, registration);
+
+    let first = server.push(&keys, b"first").unwrap();
+    assert_eq!(next_message(&mut listener).await.body, b"first");
+    wait_for_ack(&server, &first).await;
+
+    server.disconnect();
+    let second = server.push(&keys, b"second").unwrap();
+    let message = next_message(&mut listener).await;
+
+    assert_eq!(message.body, b"second");
+    assert_eq!(message.persistent_id.as_deref(), Some(second.as_str()));
+    assert_eq!(server.logins().len(), 2);
+    wait_for_ack(&server, &second).await;
+}
+
+#[tokio::test]
+async fn acknowledges_a_push_it_cannot_decrypt() {
+    let (server, registration) = registered().await;
+    let keys = registration.keys.clone();
+    let mut listener = listener(&server, registration);
+
+    let other = register(
+        &reqwest::Client::new(),
+        &server.endpoints(),
+        "other",
+        "project",
+        "key",
+        None,
+    )
+    .await
+    .expect("registration should succeed")
+    .keys;
+    let payload = EncryptedPayload::encrypt(
+        &other.public_key,
+        &other.auth_secret,
+        b"not for us",
+        ContentEncoding::Aes128Gcm,
+    )
+    .unwrap();
+    let undecodable = server.push_payload(&payload);
+
+    let error = tokio::time::timeout(Duration::from_secs(10), listener.next())
+        .await
+        .expect("the message should arrive")
+        .err()
+        .expect("the message should fail to decrypt");
+    assert!(matches!(
+        error,
+        Error::UndecodableMessage { persistent_id: Some(ref id), .. } if *id == undecodable
+    ));
+    assert!(listener.received_persistent_ids().contains(&undecodable));
+    wait_for_ack(&server, &undecodable).await;
+
+    server.push(&keys, b"hello").unwrap();
+    assert_eq!(next_message(&mut listener).await.body, b"hello");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn saves_received_messages_to_the_store() {
+    let (server, registration) = registered().await;
+    let persistent_id = server.push(&registration.keys, b"hello").unwrap();
+
+    let path = std::env::temp_dir().join(format!(
+        "fcm-push-listener-test-{}.json",
+        std::process::id()
+    ));
+    let mut listener =
+        listener(&server, registration).with_store(FileRegistrationStore::new(&path));
+    next_message(&mut listener).await;
+
+    let saved = async {
+        loop {
+            let state = FileRegistrationStore::new(&path).load().unwrap();
+            if state.is_some_and(|state| state.received_persistent_ids.contains(&persistent_id)) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    let result = tokio::time::timeout(Duration::from_secs(5), saved).await;
+    let _ = std::fs::remove_file(&path);
+    result.expect("the received message should be saved");
+}
+
+#[tokio::test]
+async fn connects_with_a_pinned_server_key() {
+    let (server, registration) = registered().await;
+    let persistent_id = server.push(&registration.keys, b"hello").unwrap();
+
+    let mut options = server.connection_options();
+    options.pinned_public_keys =
+        vec![ConnectionOptions::public_key_pin(server.certificate()).unwrap()];
+    let mut listener = listener(&server, registration).with_connection_options(options);
+
+    assert_eq!(next_message(&mut listener).await.body, b"hello");
+    wait_for_ack(&server, &persistent_id).await;
+}
+
+#[tokio::test]
+async fn refuses_a_server_without_a_pinned_key() {
+    let (server, registration) = registered().await;
+    server.push(&registration.keys, b"hello").unwrap();
+
+    let mut options = server.connection_options();
+    options.pinned_public_keys = vec![[0; 32]];
+    let mut listener = listener(&server, registration).with_connection_options(options);
+
+    let result = tokio::time::timeout(Duration::from_secs(10), listener.next())
+        .await
+        .expect("the connection attempt should end");
+    assert!(result.is_err());
+    assert!(server.logins().is_empty());
+}
+
+/// checks the ES256 signature of a VAPID token against the signer's public key, returns the claims
+fn verify_vapid_token(token: &str, public_key: &[u8]) -> serde_json::Value {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use openssl::bn::{BigNum, BigNumContext};
+    use openssl::ec::{EcGroup, EcKey, EcPoint};
+    use openssl::ecdsa::EcdsaSig;
+    use openssl::nid::Nid;
+
+    let (signing_input, signature) = token.rsplit_once('.').unwrap();
+    let (header, claims) = signing_input.split_once('.').unwrap();
+    let header: serde_json::Value =
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).unwrap()).unwrap();
+    assert_eq!(header["alg"], "ES256");
+
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let mut context = BigNumContext::new().unwrap();
+    let point = EcPoint::from_bytes(&group, public_key, &mut context).unwrap();
+    let public_key = EcKey::from_public_key(&group, &point).unwrap();
+    let signature = URL_SAFE_NO_PAD.decode(signature).unwrap();
+    assert_eq!(signature.len(), 64);
+    let signature = EcdsaSig::from_private_components(
+        BigNum::from_slice(&signature[..32]).unwrap(),
+        BigNum::from_slice(&signature[32..]).unwrap(),
+    )
+    .unwrap();
+    let digest = openssl::sha::sha256(signing_input.as_bytes());
+    assert!(signature.verify(&digest, &public_key).unwrap());
+
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn sends_a_vapid_signed_push() {
+    let server = FakeServer::start().await.expect("fake server should start");
+    let vapid = VapidKeys::generate().unwrap();
+    let registration = register(
+        &reqwest::Client::new(),
+        &server.endpoints(),
+        "app",
+        "project",
+        "key",
+        Some(&vapid.application_server_key()),
+    )
+    .await
+    .expect("registration should succeed");
+    let subscription = registration.subscription(&server.endpoints());
+    let mut listener = listener(&server, registration);
+
+    let unsigned = PushSender::new(reqwest::Client::new())
+        .send(&subscription, b"unsigned", &PushOptions::default())
+        .await;
+    assert!(matches!(
+        unsigned,
+        Err(Error::PushRejected { status: 403, .. })
+    ));
+
+    let sender = PushSender::new(reqwest::Client::new())
+        .with_vapid(vapid.clone(), "mailto:push@example.com");
+    sender
+        .send(&subscription, b"hello", &PushOptions::default())
+        .await
+        .expect("the push should be accepted");
+    assert_eq!(next_message(&mut listener).await.body, b"hello");
+
+    let requests = server.push_requests();
+    assert_eq!(requests.len(), 1);
+    let authorization = requests[0]
+        .headers
+        .iter()
+        .find(|(name, _)| name == "authorization")
+        .map(|(_, value)| value.as_str())
+        .expect("the push should carry a VAPID authorization");
+    let parameters = authorization.strip_prefix("vapid ").unwrap();
+    let parameter = |name: &str| {
+        parameters
+            .split(',')
+            .find_map(|parameter| parameter.trim().strip_prefix(name))
+            .unwrap()
+    };
+    assert_eq!(parameter("k="), vapid.application_server_key());
+
+    let claims = verify_vapid_token(parameter("t="), &vapid.public_key());
+    let endpoint = url::Url::parse(&subscription.endpoint).unwrap();
+    assert_eq!(claims["aud"], endpoint.origin().ascii_serialization());
+    assert_eq!(claims["sub"], "mailto:push@example.com");
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let expires = claims["exp"].as_u64().unwrap();
+    assert!(expires > now && expires <= now + 24 * 60 * 60);
+}
+
+#[tokio::test]
+async fn rejected_pushes_report_the_status() {
+    let (server, registration) = registered().await;
+    let http = reqwest::Client::new();
+    let sender = PushSender::new(http.clone());
+
+    let mut unknown = registration.subscription(&server.endpoints());
+    unknown.endpoint = format!("{}/unknown-token", server.endpoints().fcm_send);
+    let error = sender
+        .send(&unknown, b"hello", &PushOptions::default())
+        .await
+        .unwrap_err();
+    assert!(matches!(error, Error::PushRejected { status: 404, .. }));
+
+    let subscription = registration.subscription(&server.endpoints());
+    assert!(registration
+        .unregister(&http, &server.endpoints())
+        .await
+        .is_complete());
+    let error = sender
+        .send(&subscription, b"hello", &PushOptions::default())
+        .await
+        .unwrap_err();
+    assert!(matches!(error, Error::PushRejected { status: 410, .. }));
+    assert!(server.push_requests().is_empty());
+}
+
+/// keeps saved states in memory, and fails saves while `failing` is set
+#[derive(Clone, Default)]
+struct MemoryStore {
+    saved: Arc<Mutex<Option<StoredRegistration>>>,
+    attempts: Arc<AtomicUsize>,
+    failing: Arc<AtomicBool>,
+}
+
+impl RegistrationStore for MemoryStore {
+    fn load(&self) -> Result<Option<StoredRegistration>, Error> {
+        Ok(self.saved.lock().unwrap().clone())
+    }
+
+    fn save(&self, state: &StoredRegistration) -> Result<(), Error> {
+        self.attempts.fetch_add(1, Ordering::SeqCst);
+        if self.failing.load(Ordering::SeqCst) {
+            return Err(Error::Storage(
+                "registration write",
+                std::io::Error::other("store unavailable"),
+            ));
+        }
+        *self.saved.lock().unwrap() = Some(state.clone());
+        Ok(())
+    }
+}
+
+impl MemoryStore {
+    fn saved_ids(&self) -> Vec<String> {
+        self.saved
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|state| state.received_persistent_ids.clone())
+            .unwrap_or_default()
+    }
+
+    async fn wait_for_attempt(&self) {
+        let attempted = async {
+            while self.attempts.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), attempted)
+            .await
+            .expect("the listener should try to save");
+    }
+}
+
+#[tokio::test]
+async fn flush_saves_what_the_background_save_missed() {
+    let (server, registration) = registered().await;
+    let persistent_id = server.push(&registration.keys, b"hello").unwrap();
+
+    let store = MemoryStore::default();
+    store.failing.store(true, Ordering::SeqCst);
+    let mut listener = listener(&server, registration).with_store(store.clone());
+    next_message(&mut listener).await;
+    store.wait_for_attempt().await;
+    assert!(store.saved_ids().is_empty());
+
+    assert!(listener.flush().is_err());
+    store.failing.store(false, Ordering::SeqCst);
+    listener.flush().expect("the flush should save");
+    assert!(store.saved_ids().contains(&persistent_id));
+
+    // nothing left to save
+    let attempts = store.attempts.load(Ordering::SeqCst);
+    listener.flush().unwrap();
+    assert_eq!(store.attempts.load(Ordering::SeqCst), attempts);
+}
+
+#[tokio::test]
+async fn dropping_the_listener_saves_pending_state() {
+    let (server, registration) = registered().await;
+    let persistent_id = server.push(&registration.keys, b"hello").unwrap();
+
+    let store = MemoryStore::default();
+    store.failing.store(true, Ordering::SeqCst);
+    let mut listener = listener(&server, registration).with_store(store.clone());
+    next_message(&mut listener).await;
+    store.wait_for_attempt().await;
+
+    store.failing.store(false, Ordering::SeqCst);
+    drop(listener);
+    assert!(store.saved_ids().contains(&persistent_id));
+}
+
+#[tokio::test]
+async fn tells_app_data_from_encrypted_messages() {
+    let (server, registration) = registered().await;
+    let keys = registration.keys.clone();
+    let mut listener = listener(&server, registration);
+
+    server.push(&keys, b"hello").unwrap();
+    let message = next_message(&mut listener).await;
+    assert!(message.encrypted);
+    assert_eq!(message.body, b"hello");
+
+    let persistent_id = server.push_app_data(&keys, &[("greeting", "hello")]);
+    let message = next_message(&mut listener).await;
+    assert!(!message.encrypted);
+    assert!(message.body.is_empty());
+    assert_eq!(message.app_data["greeting"], "hello");
+    wait_for_ack(&server, &persistent_id).await;
+}
+
+/// forwards to reqwest, making the fake server fail the requests to one service
+struct FailingService {
+    http: reqwest::Client,
+    server: Arc<FakeServer>,
+    path: &'static str,
+    failures: AtomicUsize,
+}
+
+impl HttpTransport for FailingService {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, TransportError>> {
+        let failing = request.url.contains(self.path)
+            && self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+        if failing {
+            self.server.fail_next(1);
+        }
+        self.http.send(request)
+    }
+}
+
+#[tokio::test]
+async fn retries_only_the_failing_registration_step() {
+    let server = Arc::new(FakeServer::start().await.expect("fake server should start"));
+    let http = FailingService {
+        http: reqwest::Client::new(),
+        server: server.clone(),
+        path: "/fcmregistrations/",
+        failures: AtomicUsize::new(2),
+    };
+    let retry = RetryPolicy {
+        initial_delay: Duration::from_millis(10),
+        ..Default::default()
+    };
+
+    register_with_retry(
+        &http,
+        &server.endpoints(),
+        &retry,
+        "app",
+        "project",
+        "key",
+        None,
+    )
+    .await
+    .expect("registration should succeed on the third attempt");
+
+    assert_eq!(http.failures.load(Ordering::SeqCst), 0);
+    assert_eq!(server.android_ids().len(), 1);
+    assert_eq!(server.gcm_tokens().len(), 1);
+    assert_eq!(server.installations().len(), 1);
+    assert_eq!(server.registrations().len(), 1);
+}
+
+#[tokio::test]
+async fn no_retry_policy_fails_on_the_first_error() {
+    let server = FakeServer::start().await.expect("fake server should start");
+    server.fail_next(1);
+
+    let error = register_with_retry(
+        &reqwest::Client::new(),
+        &server.endpoints(),
+        &RetryPolicy::none(),
+        "app",
+        "project",
+        "key",
+        None,
+    )
+    .await
+    .err()
+    .expect("the check-in should fail");
+
+    assert!(error.is_retryable());
+    assert!(server.android_ids().is_empty());
+    assert!(server.gcm_tokens().is_empty());
+}
+
+#[tokio::test]
+async fn unregister_runs_every_step() {
+    let (server, registration) = registered().await;
+
+    let report = registration
+        .unregister(&reqwest::Client::new(), &server.endpoints())
+        .await;
+
+    assert!(report.is_complete());
+    assert_eq!(report.errors().count(), 0);
+    assert!(server.registrations().is_empty());
+    assert!(server.installations().is_empty());
+    assert!(server.gcm_tokens().is_empty());
+}
+
+#[tokio::test]
+async fn unregister_goes_on_after_a_failed_step() {
+    let (server, registration) = registered().await;
+    server.fail_next(1);
+
+    let report = registration
+        .unregister(&reqwest::Client::new(), &server.endpoints())
+        .await;
+
+    assert!(!report.is_complete());
+    let errors: Vec<_> = report.errors().collect();
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(
+        errors[0],
+        ("FCM registration", Error::GoogleApi { status: 503, .. })
+    ));
+    assert!(matches!(report.installation, Some(Ok(()))));
+    assert!(matches!(report.gcm, Some(Ok(()))));
+
+    assert_eq!(server.registrations().len(), 1);
+    assert!(server.installations().is_empty());
+    assert!(server.gcm_tokens().is_empty());
+}
+
+#[tokio::test]
+async fn refreshes_an_expiring_installation_auth_token() {
+    let (server, registration) = registered().await;
+    let http = reqwest::Client::new();
+    let mut installation = registration
+        .installation
+        .expect("installation should be kept");
+    let fid = installation.fid.clone();
+    let first = installation.auth_token.value.clone();
+
+    // a token with plenty of time left is used as it is
+    let token = installation
+        .auth_token(&http, &server.endpoints())
+        .await
+        .unwrap();
+    assert_eq!(token.value, first);
+
+    installation.auth_token.expires_at = Some(SystemTime::now() + Duration::from_secs(60));
+    let token = installation
+        .auth_token(&http, &server.endpoints())
+        .await
+        .expect("the token should be refreshed")
+        .clone();
+    assert_ne!(token.value, first);
+    assert!(token
+        .expires_at
+        .is_some_and(|expires_at| expires_at > SystemTime::now() + Duration::from_secs(60 * 60)));
+    assert_eq!(installation.fid, fid);
+
+    let installations = server.installations();
+    assert_eq!(installations.len(), 1);
+    assert_eq!(installations[0].fid, fid);
+    assert_eq!(installations[0].auth_tokens, vec![first, token.value]);
+}
+
+#[tokio::test]
+async fn reconnects_when_heartbeats_go_unanswered() {
+    let (server, registration) = registered().await;
+    let keys = registration.keys.clone();
+    let mut listener = listener(&server, registration)
+        .with_heartbeat_interval(Duration::from_millis(100))
+        .with_heartbeat_timeout(Duration::from_millis(100));
+
+    server.ignore_pings(true);
+    let error = tokio::time::timeout(Duration::from_secs(5), listener.next())
+        .await
+        .expect("the unanswered heartbeat should end the wait")
+        .err()
+        .expect("the connection should go stale");
+    assert!(matches!(error, Error::ConnectionStale));
+    assert_eq!(server.logins().len(), 1);
+
+    server.ignore_pings(false);
+    server.push(&keys, b"hello").unwrap();
+    assert_eq!(next_message(&mut listener).await.body, b"hello");
+    assert_eq!(server.logins().len(), 2);
+}